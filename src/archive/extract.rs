@@ -0,0 +1,579 @@
+use crate::archive::format::*;
+use crate::*;
+
+use indicatif::*;
+
+use std::io::Read;
+use std::path::{Component, PathBuf};
+
+fn escape_err(entry: &Path, reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        format!(
+            "[{}:{}] refusing to extract {}: {}",
+            file!(),
+            line!(),
+            entry.to_string_lossy(),
+            reason
+        ),
+    )
+}
+
+/// Turns an archive member name into a path relative to the extraction root,
+/// rejecting anything that is absolute or climbs out through `..`.
+pub fn sanitize_entry_path(entry: &Path) -> std::io::Result<PathBuf> {
+    let mut sanitized = PathBuf::new();
+
+    for component in entry.components() {
+        match component {
+            Component::Normal(part) => sanitized.push(part),
+            Component::CurDir => (),
+            Component::ParentDir => return Err(escape_err(entry, "path contains '..'")),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(escape_err(entry, "path is absolute"));
+            }
+        }
+    }
+
+    Ok(sanitized)
+}
+
+// Where `target` of a symlink living in `link_parent` points to on disk. Every
+// existing prefix is resolved through the filesystem before a `..` is applied
+// to it, so a chain of symlinks can't climb out where a lexical check would
+// say it stays inside. `None` if a `..` follows a path that doesn't exist
+// yet, a symlink extracted later could still redirect it.
+fn resolve_link_target(link_parent: &Path, target: &Path) -> std::io::Result<Option<PathBuf>> {
+    let mut resolved = io_ok!(link_parent.canonicalize(), link_parent.to_string_lossy());
+    let mut missing = false;
+
+    for component in target.components() {
+        match component {
+            Component::Normal(part) => {
+                resolved.push(part);
+                match resolved.canonicalize() {
+                    Ok(canon) => resolved = canon,
+                    Err(_) => missing = true,
+                }
+            }
+            Component::ParentDir if missing => return Ok(None),
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => (),
+            Component::RootDir | Component::Prefix(_) => return Ok(None),
+        }
+    }
+
+    Ok(Some(resolved))
+}
+
+fn check_symlink_target(dest: &Path, link: &Path, target: &Path) -> std::io::Result<()> {
+    let link_parent = link.parent().unwrap_or(dest);
+    let dest_canon = io_ok!(dest.canonicalize(), dest.to_string_lossy());
+    match resolve_link_target(link_parent, target)? {
+        Some(resolved) if resolved.starts_with(&dest_canon) => Ok(()),
+        _ => Err(escape_err(
+            link,
+            &format!(
+                "symlink points outside of target: {}",
+                target.to_string_lossy()
+            ),
+        )),
+    }
+}
+
+// Creates the parent of `full_path` unless it would resolve outside of `dest`
+// through a symlink extracted earlier.
+fn prepare_entry_path(dest: &Path, full_path: &Path) -> std::io::Result<()> {
+    if let Some(parent) = full_path.parent()
+        && !create_dir_inside(parent, dest)?
+    {
+        return Err(escape_err(
+            full_path,
+            "parent directory resolves outside of target",
+        ));
+    }
+
+    // never write through a symlink already sitting at the destination
+    if let Ok(meta) = fs::symlink_metadata(full_path)
+        && meta.file_type().is_symlink()
+    {
+        io_ok!(fs::remove_file(full_path), full_path.to_string_lossy());
+    }
+
+    Ok(())
+}
+
+// The one directory every member of an archive was unpacked into, if any.
+#[derive(Default)]
+struct TopLevelDir {
+    name: Option<PathBuf>,
+    mixed: bool,
+}
+
+impl TopLevelDir {
+    fn add(&mut self, rel_path: &Path, is_dir: bool) {
+        let mut components = rel_path.components();
+        let first = components
+            .next()
+            .map(|first| PathBuf::from(first.as_os_str()));
+        if !is_dir && components.next().is_none() {
+            self.mixed = true;
+        }
+        match &self.name {
+            None => self.name = first,
+            Some(name) if Some(name) != first.as_ref() => self.mixed = true,
+            _ => (),
+        }
+    }
+
+    fn finish(self) -> Option<PathBuf> {
+        if self.mixed { None } else { self.name }
+    }
+}
+
+fn setup_progress_bar(archive: &Path, len: u64) -> ProgressBar {
+    let pb = ProgressBar::new(len);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("[{prefix}]: {msg}: [{bar:40.bold.dim}] {bytes}/{total_bytes} ({percent}%)")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    pb.set_prefix(
+        archive
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    );
+    pb.set_message("Extracting");
+    pb
+}
+
+fn unpack_tar<R: Read>(reader: R, dest: &Path) -> std::io::Result<Option<PathBuf>> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    let mut top_level = TopLevelDir::default();
+
+    for entry in io_ok!(archive.entries()) {
+        let mut entry = io_ok!(entry);
+        let entry_path = io_ok!(entry.path()).into_owned();
+        let rel_path = sanitize_entry_path(&entry_path)?;
+        if rel_path.as_os_str().is_empty() {
+            continue;
+        }
+        let full_path = dest.join(&rel_path);
+        let entry_type = entry.header().entry_type();
+        top_level.add(&rel_path, entry_type.is_dir());
+
+        prepare_entry_path(dest, &full_path)?;
+
+        if entry_type.is_symlink() {
+            let target = io_ok!(entry.link_name())
+                .ok_or_else(|| escape_err(&entry_path, "symlink without target"))?;
+            check_symlink_target(dest, &full_path, &target)?;
+        } else if entry_type.is_hard_link() {
+            let target = io_ok!(entry.link_name())
+                .ok_or_else(|| escape_err(&entry_path, "hard link without target"))?;
+            let link_src = dest.join(sanitize_entry_path(&target)?);
+            if !link_src.is_subpath_of(dest)? {
+                return Err(escape_err(
+                    &entry_path,
+                    "hard link points outside of target",
+                ));
+            }
+            if full_path.exists() {
+                io_ok!(fs::remove_file(&full_path), full_path.to_string_lossy());
+            }
+            io_ok!(
+                fs::hard_link(&link_src, &full_path),
+                "{} -> {}",
+                full_path.to_string_lossy(),
+                link_src.to_string_lossy()
+            );
+            continue;
+        }
+
+        io_ok!(entry.unpack(&full_path), full_path.to_string_lossy());
+    }
+
+    Ok(top_level.finish())
+}
+
+fn unpack_zip<R: Read + std::io::Seek>(reader: R, dest: &Path) -> std::io::Result<Option<PathBuf>> {
+    let mut archive = zip::ZipArchive::new(reader).map_err(std::io::Error::other)?;
+    let mut top_level = TopLevelDir::default();
+
+    for idx in 0..archive.len() {
+        let mut entry = archive.by_index(idx).map_err(std::io::Error::other)?;
+        let entry_path = PathBuf::from(entry.name().map_err(std::io::Error::other)?.as_ref());
+        let rel_path = sanitize_entry_path(&entry_path)?;
+        if rel_path.as_os_str().is_empty() {
+            continue;
+        }
+        let full_path = dest.join(&rel_path);
+        top_level.add(&rel_path, entry.is_dir());
+
+        if entry.is_dir() {
+            if !create_dir_inside(&full_path, dest)? {
+                return Err(escape_err(
+                    &entry_path,
+                    "directory resolves outside of target",
+                ));
+            }
+            continue;
+        }
+
+        prepare_entry_path(dest, &full_path)?;
+
+        if entry.is_symlink() {
+            let mut target = String::new();
+            io_ok!(
+                entry.read_to_string(&mut target),
+                entry_path.to_string_lossy()
+            );
+            check_symlink_target(dest, &full_path, Path::new(&target))?;
+            io_ok!(
+                std::os::unix::fs::symlink(&target, &full_path),
+                full_path.to_string_lossy()
+            );
+            continue;
+        }
+
+        let mut out = io_ok!(fs::File::create(&full_path), full_path.to_string_lossy());
+        io_ok!(
+            std::io::copy(&mut entry, &mut out),
+            full_path.to_string_lossy()
+        );
+
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            io_ok!(
+                fs::set_permissions(&full_path, fs::Permissions::from_mode(mode & 0o7777)),
+                full_path.to_string_lossy()
+            );
+        }
+    }
+
+    Ok(top_level.finish())
+}
+
+fn uncompressed_name(archive: &Path) -> std::io::Result<PathBuf> {
+    let file_name = archive
+        .file_name()
+        .ok_or_else(|| escape_err(archive, "archive has no file name"))?;
+    let stem = Path::new(file_name);
+
+    match stem.extension().and_then(|ext| ext.to_str()) {
+        Some("gz" | "xz" | "bz2" | "zst" | "z" | "Z") => {
+            Ok(PathBuf::from(stem.file_stem().unwrap()))
+        }
+        _ => Ok(PathBuf::from(format!("{}.out", stem.to_string_lossy()))),
+    }
+}
+
+/// Unpacks `archive` of the given `format` into `dest`. Members escaping `dest`
+/// abort the extraction with `PermissionDenied`. Returns the directory below
+/// `dest` all members were unpacked into, if the archive has a single one.
+pub fn extract_archive<A, D>(
+    archive: A,
+    format: ArchiveFormat,
+    dest: D,
+) -> std::io::Result<Option<PathBuf>>
+where
+    A: AsRef<std::path::Path>,
+    D: AsRef<std::path::Path>,
+{
+    let archive = archive.as_ref();
+    let dest = dest.as_ref();
+    let archive_utf8 = archive.to_string_lossy();
+
+    println!("extracting: {:?} -> {:?}", archive, dest);
+    io_ok!(fs::create_dir_all(dest), dest.to_string_lossy());
+
+    let file = io_ok!(fs::File::open(archive), archive_utf8);
+    let pb = setup_progress_bar(archive, io_ok!(file.metadata(), archive_utf8).len());
+
+    let top_level = match format {
+        ArchiveFormat::Tar(compression) => {
+            unpack_tar(decoder(compression, pb.wrap_read(file))?, dest)?
+        }
+        ArchiveFormat::Zip => unpack_zip(pb.wrap_read(file), dest)?,
+        ArchiveFormat::Compressed(compression) => {
+            let out_path = dest.join(uncompressed_name(archive)?);
+            prepare_entry_path(dest, &out_path)?;
+
+            let mut out = io_ok!(fs::File::create(&out_path), out_path.to_string_lossy());
+            io_ok!(
+                std::io::copy(&mut decoder(compression, pb.wrap_read(file))?, &mut out),
+                archive_utf8
+            );
+            None
+        }
+        ArchiveFormat::Unknown => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "[{}:{}] unrecognized archive format: {}",
+                    file!(),
+                    line!(),
+                    archive_utf8
+                ),
+            ));
+        }
+    };
+
+    pb.finish_with_message("Extracted");
+    Ok(top_level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+    use std::io::Write;
+
+    type RawEntry<'a> = (&'a str, tar::EntryType, &'a str, &'a [u8]);
+
+    // Writes a raw ustar member, bypassing the path validation of tar::Builder.
+    fn raw_entry(tar: &mut Vec<u8>, name: &str, kind: tar::EntryType, link: &str, data: &[u8]) {
+        let mut header = tar::Header::new_ustar();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_entry_type(kind);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+
+        tar.extend_from_slice(header.as_bytes());
+        tar.extend_from_slice(data);
+        tar.resize(tar.len().div_ceil(512) * 512, 0);
+    }
+
+    fn tar_bytes(entries: &[RawEntry]) -> Vec<u8> {
+        let mut tar = Vec::new();
+        for (name, kind, link, data) in entries {
+            raw_entry(&mut tar, name, *kind, link, data);
+        }
+        tar.resize(tar.len() + 1024, 0);
+        tar
+    }
+
+    fn write_tar_gz(path: &Path, entries: &[RawEntry]) {
+        let tar = tar_bytes(entries);
+        let mut gz = flate2::write::GzEncoder::new(
+            fs::File::create(path).unwrap(),
+            flate2::Compression::default(),
+        );
+        gz.write_all(&tar).unwrap();
+        gz.finish().unwrap();
+    }
+
+    #[test]
+    fn test_detect_and_extract_tar_gz() {
+        let dir = scratch_dir("extract-targz");
+        let archive = dir.join("pkg.bin");
+        write_tar_gz(
+            &archive,
+            &[
+                ("pkg-1.0/README", tar::EntryType::Regular, "", b"hello"),
+                ("pkg-1.0/link", tar::EntryType::Symlink, "README", b""),
+            ],
+        );
+
+        let format = detect_format(&archive).unwrap();
+        assert_eq!(format, ArchiveFormat::Tar(Compression::Gzip));
+
+        let out = dir.join("out");
+        let top_level = extract_archive(&archive, format, &out).unwrap();
+        assert_eq!(top_level, Some(PathBuf::from("pkg-1.0")));
+        assert_eq!(fs::read(out.join("pkg-1.0/README")).unwrap(), b"hello");
+        assert_eq!(fs::read(out.join("pkg-1.0/link")).unwrap(), b"hello");
+
+        // members next to each other have no directory in common
+        write_tar_gz(
+            &archive,
+            &[
+                ("pkg-1.0/README", tar::EntryType::Regular, "", b"hello"),
+                ("INSTALL", tar::EntryType::Regular, "", b"hello"),
+            ],
+        );
+        let top_level = extract_archive(&archive, format, &out).unwrap();
+        assert_eq!(top_level, None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extract_xz_bz2_zst_tarballs() {
+        let dir = scratch_dir("extract-compressed");
+        let tar = tar_bytes(&[("pkg-1.0/README", tar::EntryType::Regular, "", b"hello")]);
+
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(&tar).unwrap();
+        let mut bz2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        bz2.write_all(&tar).unwrap();
+        let cases = [
+            ("pkg.tar.xz", Compression::Xz, xz.finish().unwrap()),
+            ("pkg.tar.bz2", Compression::Bzip2, bz2.finish().unwrap()),
+            (
+                "pkg.tar.zst",
+                Compression::Zstd,
+                zstd::stream::encode_all(tar.as_slice(), 0).unwrap(),
+            ),
+        ];
+
+        for (name, compression, data) in cases {
+            let archive = dir.join(name);
+            fs::write(&archive, data).unwrap();
+            let format = detect_format(&archive).unwrap();
+            assert_eq!(format, ArchiveFormat::Tar(compression));
+
+            let out = dir.join(format!("{}.out", name));
+            extract_archive(&archive, format, &out).unwrap();
+            assert_eq!(fs::read(out.join("pkg-1.0/README")).unwrap(), b"hello");
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extract_zip() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("extract-zip");
+        let archive = dir.join("pkg.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        zip.add_directory("pkg-1.0/bin/", options).unwrap();
+        zip.start_file("pkg-1.0/bin/hello", options.unix_permissions(0o755))
+            .unwrap();
+        zip.write_all(b"#!/bin/sh\n").unwrap();
+        zip.add_symlink("pkg-1.0/hi", "bin/hello", options).unwrap();
+        zip.finish().unwrap();
+
+        let format = detect_format(&archive).unwrap();
+        assert_eq!(format, ArchiveFormat::Zip);
+
+        let out = dir.join("out");
+        let top_level = extract_archive(&archive, format, &out).unwrap();
+        assert_eq!(top_level, Some(PathBuf::from("pkg-1.0")));
+        assert_eq!(fs::read(out.join("pkg-1.0/hi")).unwrap(), b"#!/bin/sh\n");
+        let mode = fs::metadata(out.join("pkg-1.0/bin/hello"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_detect_plain_compressed_file() {
+        let dir = scratch_dir("extract-gz");
+        let archive = dir.join("notes.txt.gz");
+        let mut gz = flate2::write::GzEncoder::new(
+            fs::File::create(&archive).unwrap(),
+            flate2::Compression::default(),
+        );
+        gz.write_all(b"plain text").unwrap();
+        gz.finish().unwrap();
+
+        let format = detect_format(&archive).unwrap();
+        assert_eq!(format, ArchiveFormat::Compressed(Compression::Gzip));
+
+        assert_eq!(extract_archive(&archive, format, &dir).unwrap(), None);
+        assert_eq!(fs::read(dir.join("notes.txt")).unwrap(), b"plain text");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reject_escaping_entries() {
+        let dir = scratch_dir("extract-escape");
+        let out = dir.join("out");
+        let cases: [&[RawEntry]; 4] = [
+            &[("../evil", tar::EntryType::Regular, "", b"x")],
+            &[("/tmp/evil", tar::EntryType::Regular, "", b"x")],
+            &[("link", tar::EntryType::Symlink, "../../etc", b"")],
+            &[("dir/hard", tar::EntryType::Link, "../../outside", b"")],
+        ];
+
+        for (idx, entries) in cases.iter().enumerate() {
+            let archive = dir.join(format!("evil-{}.tar.gz", idx));
+            write_tar_gz(&archive, entries);
+            let err = extract_archive(&archive, detect_format(&archive).unwrap(), &out)
+                .expect_err("escaping entry must be rejected");
+            assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        }
+        assert!(!dir.join("evil").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reject_write_through_symlinked_dir() {
+        let dir = scratch_dir("extract-symdir");
+        let out = dir.join("out");
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        fs::create_dir_all(&out).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), out.join("sub")).unwrap();
+
+        let archive = dir.join("evil.tar.gz");
+        write_tar_gz(&archive, &[("sub/file", tar::EntryType::Regular, "", b"x")]);
+
+        let err = extract_archive(&archive, detect_format(&archive).unwrap(), &out).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(!dir.join("outside/file").exists());
+
+        // nothing is created below the symlink before the entry is refused
+        write_tar_gz(
+            &archive,
+            &[("sub/newdir/file", tar::EntryType::Regular, "", b"x")],
+        );
+        let err = extract_archive(&archive, detect_format(&archive).unwrap(), &out).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(!dir.join("outside/newdir").exists());
+
+        let archive = dir.join("evil.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        zip.add_directory("sub/zipdir/", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.finish().unwrap();
+        let err = extract_archive(&archive, detect_format(&archive).unwrap(), &out).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(!dir.join("outside/zipdir").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reject_chained_symlinks() {
+        let dir = scratch_dir("extract-symchain");
+        let cases: [&[RawEntry]; 2] = [
+            // `out/d/..` is `out` lexically but the parent of `out` on disk
+            &[
+                ("d", tar::EntryType::Symlink, ".", b""),
+                ("e", tar::EntryType::Symlink, "d/..", b""),
+            ],
+            // `x` doesn't exist yet when `e` is checked
+            &[
+                ("e", tar::EntryType::Symlink, "x/..", b""),
+                ("x", tar::EntryType::Symlink, ".", b""),
+            ],
+        ];
+
+        for (idx, entries) in cases.iter().enumerate() {
+            let out = dir.join(format!("out-{}", idx));
+            let archive = dir.join(format!("chain-{}.tar.gz", idx));
+            write_tar_gz(&archive, entries);
+            let err = extract_archive(&archive, detect_format(&archive).unwrap(), &out)
+                .expect_err("symlink chain must be rejected");
+            assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied, "{}", err);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
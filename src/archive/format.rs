@@ -0,0 +1,106 @@
+use crate::*;
+
+use std::io::Read;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Bzip2,
+    Zstd,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ArchiveFormat {
+    Tar(Compression),
+    Zip,
+    Compressed(Compression),
+    Unknown,
+}
+
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+fn detect_compression(magic: &[u8]) -> Compression {
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Compression::Gzip
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Compression::Xz
+    } else if magic.starts_with(b"BZh") {
+        Compression::Bzip2
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Compression::Zstd
+    } else {
+        Compression::None
+    }
+}
+
+pub fn decoder<'a, R: Read + 'a>(
+    compression: Compression,
+    reader: R,
+) -> std::io::Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)),
+        Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+    })
+}
+
+// Fills `buffer` as far as the stream allows, a short stream is not an error here.
+fn read_prefix<R: Read>(mut reader: R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn is_tar_header(header: &[u8]) -> bool {
+    header.len() >= TAR_MAGIC_OFFSET + TAR_MAGIC.len()
+        && &header[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()] == TAR_MAGIC
+}
+
+/// Detects the archive format of `path` from its content. The extension is only
+/// consulted for pre-POSIX tarballs which carry no magic at all.
+pub fn detect_format<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<ArchiveFormat> {
+    let path_utf8 = path.as_ref().to_string_lossy();
+    let mut header = [0u8; 512];
+
+    let file = io_ok!(fs::File::open(path.as_ref()), path_utf8);
+    let len = io_ok!(read_prefix(file, &mut header), path_utf8);
+    let header = &header[..len];
+
+    if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+        return Ok(ArchiveFormat::Zip);
+    }
+
+    let compression = detect_compression(header);
+    if compression == Compression::None {
+        if is_tar_header(header) {
+            return Ok(ArchiveFormat::Tar(Compression::None));
+        }
+        if path.as_ref().extension().is_some_and(|ext| ext == "tar") {
+            return Ok(ArchiveFormat::Tar(Compression::None));
+        }
+        return Ok(ArchiveFormat::Unknown);
+    }
+
+    let file = io_ok!(fs::File::open(path.as_ref()), path_utf8);
+    let mut inner = [0u8; 512];
+    let inner_len = io_ok!(
+        read_prefix(io_ok!(decoder(compression, file), path_utf8), &mut inner),
+        path_utf8
+    );
+
+    if is_tar_header(&inner[..inner_len]) {
+        Ok(ArchiveFormat::Tar(compression))
+    } else {
+        Ok(ArchiveFormat::Compressed(compression))
+    }
+}
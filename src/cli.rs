@@ -0,0 +1,138 @@
+use clap::{Args, Parser, Subcommand};
+
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "upkg",
+    version,
+    about = "Build and install packages from lua pkgbuilds"
+)]
+pub struct Cli {
+    #[command(flatten)]
+    pub dirs: DirArgs,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Args, Debug)]
+pub struct DirArgs {
+    /// Additional config file, read after the system and user config
+    #[arg(long, value_name = "FILE", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Root directory packages are installed into [default: /]
+    #[arg(long, value_name = "DIR", global = true)]
+    pub install_root: Option<PathBuf>,
+
+    /// Directory sources are fetched into and built from [default: <pkgbuild dir>/build]
+    #[arg(long, value_name = "DIR", global = true)]
+    pub build_dir: Option<PathBuf>,
+
+    /// Number of parallel jobs exposed to the pkgbuild [default: number of cpus]
+    #[arg(long, short = 'j', value_name = "N", global = true)]
+    pub jobs: Option<u32>,
+
+    /// Never touch the network, build from existing clones and downloads only
+    #[arg(long, global = true)]
+    pub offline: bool,
+
+    /// Fail if the sources differ from pkgbuild.lock instead of updating it
+    #[arg(long, global = true)]
+    pub locked: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct StageArgs {
+    /// Do not check or resolve dependencies
+    #[arg(long, short = 'd')]
+    pub nodeps: bool,
+
+    /// Do not verify source checksums
+    #[arg(long)]
+    pub skip_verify: bool,
+
+    /// Do not extract downloaded archives or apply patches
+    #[arg(long)]
+    pub noextract: bool,
+
+    /// Do not run the Prepare() stage
+    #[arg(long)]
+    pub noprepare: bool,
+
+    /// Do not run the Build() stage
+    #[arg(long)]
+    pub nobuild: bool,
+
+    /// Do not run the Check() stage
+    #[arg(long)]
+    pub nocheck: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Fetch, verify and extract sources, run Prepare(), Build(), Check() and
+    /// Install() into a staging dir and pack the result into a package archive
+    Build {
+        /// Path to the pkgbuild.lua
+        pkgbuild: PathBuf,
+        #[command(flatten)]
+        stages: StageArgs,
+    },
+    /// Download the sources of a pkgbuild
+    Fetch {
+        /// Path to the pkgbuild.lua
+        pkgbuild: PathBuf,
+    },
+    /// Fetch the newest sources ignoring pkgbuild.lock and write it anew
+    Update {
+        /// Path to the pkgbuild.lua
+        pkgbuild: PathBuf,
+    },
+    /// Verify already downloaded sources against their checksums
+    Verify {
+        /// Path to the pkgbuild.lua
+        pkgbuild: PathBuf,
+    },
+    /// Build a pkgbuild and install the resulting package, or install an
+    /// already built .upkg.tar.zst package. Missing dependencies are taken
+    /// from the other packages in the directory of the package
+    Install {
+        /// Path to the pkgbuild.lua or a .upkg.tar.zst package
+        target: PathBuf,
+        /// Mark the package as installed as a dependency
+        #[arg(long)]
+        asdeps: bool,
+        #[command(flatten)]
+        stages: StageArgs,
+    },
+    /// Remove installed packages and the files they own
+    Remove {
+        /// Names of installed packages
+        #[arg(required = true)]
+        names: Vec<String>,
+        /// Also remove packages depending on the given ones
+        #[arg(long)]
+        cascade: bool,
+        /// Also remove dependencies no other package needs anymore
+        #[arg(long)]
+        recursive: bool,
+    },
+    /// Print the package metadata of a pkgbuild
+    Info {
+        /// Path to the pkgbuild.lua
+        pkgbuild: PathBuf,
+    },
+    /// Manage the source cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// Remove the cached git mirrors that no running upkg is using
+    Clean,
+}
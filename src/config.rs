@@ -0,0 +1,309 @@
+use crate::*;
+
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+pub static SYSTEM_CONFIG_PATH: &str = "/etc/upkg.toml";
+
+/// One source of settings, every field left out falls through to the layer below.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigLayer {
+    pub install_root: Option<PathBuf>,
+    pub build_root: Option<PathBuf>,
+    pub cache_dir: Option<PathBuf>,
+    pub db_path: Option<PathBuf>,
+    pub jobs: Option<u32>,
+    pub cflags: Option<String>,
+    pub cxxflags: Option<String>,
+    pub ldflags: Option<String>,
+    pub makeflags: Option<String>,
+    pub packager: Option<String>,
+    pub offline: Option<bool>,
+    pub locked: Option<bool>,
+    /// Credentials for git remotes by host, layers add to the hosts below.
+    pub git_auth: Option<HashMap<String, git_auth::GitAuth>>,
+}
+
+/// Effective settings after merging defaults, `/etc/upkg.toml`, the user config,
+/// `UPKG_*` environment variables and command line overrides, in that order.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub install_root: PathBuf,
+    pub build_root: Option<PathBuf>,
+    pub cache_dir: PathBuf,
+    pub db_path: Option<PathBuf>,
+    pub jobs: u32,
+    pub cflags: String,
+    pub cxxflags: String,
+    pub ldflags: String,
+    pub makeflags: String,
+    pub packager: String,
+    /// Never fetch from the network, build from cached sources only.
+    pub offline: bool,
+    /// Fail instead of updating `pkgbuild.lock` when sources differ from it.
+    pub locked: bool,
+    pub git_auth: HashMap<String, git_auth::GitAuth>,
+}
+
+fn xdg_dir(xdg_var: &str, home_fallback: &str) -> Option<PathBuf> {
+    match std::env::var_os(xdg_var) {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(home_fallback)),
+    }
+}
+
+pub fn user_config_path() -> Option<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config").map(|dir| dir.join("upkg").join("config.toml"))
+}
+
+impl ConfigLayer {
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<ConfigLayer> {
+        let path_utf8 = path.as_ref().to_string_lossy();
+        let data = io_ok!(fs::read_to_string(path.as_ref()), path_utf8);
+
+        toml::from_str(&data).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("[{}:{}] {}: {}", file!(), line!(), path_utf8, err),
+            )
+        })
+    }
+
+    // A missing config file is not an error, any other failure to read it is.
+    fn from_optional_file<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<ConfigLayer> {
+        if path.as_ref().exists() {
+            ConfigLayer::from_file(path)
+        } else {
+            Ok(ConfigLayer::default())
+        }
+    }
+
+    pub fn from_env() -> std::io::Result<ConfigLayer> {
+        let var = |name: &str| std::env::var(name).ok().filter(|val| !val.is_empty());
+
+        let jobs = match var("UPKG_JOBS") {
+            Some(jobs) => Some(jobs.parse::<u32>().map_err(|err| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("[{}:{}] UPKG_JOBS={}: {}", file!(), line!(), jobs, err),
+                )
+            })?),
+            None => None,
+        };
+        let offline = match var("UPKG_OFFLINE") {
+            Some(offline) => Some(offline.parse::<bool>().map_err(|err| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "[{}:{}] UPKG_OFFLINE={}: {}",
+                        file!(),
+                        line!(),
+                        offline,
+                        err
+                    ),
+                )
+            })?),
+            None => None,
+        };
+
+        Ok(ConfigLayer {
+            install_root: var("UPKG_INSTALL_ROOT").map(PathBuf::from),
+            build_root: var("UPKG_BUILD_ROOT").map(PathBuf::from),
+            cache_dir: var("UPKG_CACHE_DIR").map(PathBuf::from),
+            db_path: var("UPKG_DB_PATH").map(PathBuf::from),
+            jobs,
+            cflags: var("UPKG_CFLAGS"),
+            cxxflags: var("UPKG_CXXFLAGS"),
+            ldflags: var("UPKG_LDFLAGS"),
+            makeflags: var("UPKG_MAKEFLAGS"),
+            packager: var("UPKG_PACKAGER"),
+            offline,
+            locked: None,
+            git_auth: None,
+        })
+    }
+
+    /// Overwrites every field of `self` that is set in `upper`, hosts of
+    /// `git_auth` are overwritten one by one.
+    pub fn merge(&mut self, upper: ConfigLayer) {
+        if let Some(hosts) = upper.git_auth {
+            self.git_auth.get_or_insert_default().extend(hosts);
+        }
+
+        macro_rules! take {
+            ($($field:ident),*) => {
+                $(if upper.$field.is_some() { self.$field = upper.$field; })*
+            };
+        }
+        take!(
+            install_root,
+            build_root,
+            cache_dir,
+            db_path,
+            jobs,
+            cflags,
+            cxxflags,
+            ldflags,
+            makeflags,
+            packager,
+            offline,
+            locked
+        );
+    }
+}
+
+impl Config {
+    /// Loads all config layers, `extra_file` is read after the user config and
+    /// `overrides` (usually from the command line) win over everything else.
+    pub fn load(
+        extra_file: Option<&std::path::Path>,
+        overrides: ConfigLayer,
+    ) -> std::io::Result<Config> {
+        let mut layer = ConfigLayer::from_optional_file(SYSTEM_CONFIG_PATH)?;
+        if let Some(user_config) = user_config_path() {
+            layer.merge(ConfigLayer::from_optional_file(user_config)?);
+        }
+        if let Some(extra_file) = extra_file {
+            layer.merge(ConfigLayer::from_file(extra_file)?);
+        }
+        layer.merge(ConfigLayer::from_env()?);
+        layer.merge(overrides);
+
+        Ok(Config::from_layer(layer))
+    }
+
+    pub fn from_layer(layer: ConfigLayer) -> Config {
+        let jobs = layer.jobs.filter(|jobs| *jobs > 0).unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|jobs| jobs.get() as u32)
+                .unwrap_or(1)
+        });
+        let cflags = layer
+            .cflags
+            .unwrap_or_else(|| String::from("-O2 -pipe -fno-plt"));
+
+        Config {
+            install_root: layer.install_root.unwrap_or_else(|| PathBuf::from("/")),
+            build_root: layer.build_root,
+            cache_dir: layer
+                .cache_dir
+                .or_else(|| xdg_dir("XDG_CACHE_HOME", ".cache").map(|dir| dir.join("upkg")))
+                .unwrap_or_else(|| PathBuf::from("/var/cache/upkg")),
+            db_path: layer.db_path,
+            jobs,
+            cxxflags: layer.cxxflags.unwrap_or_else(|| cflags.clone()),
+            cflags,
+            ldflags: layer.ldflags.unwrap_or_default(),
+            makeflags: layer.makeflags.unwrap_or_else(|| format!("-j{}", jobs)),
+            packager: layer
+                .packager
+                .unwrap_or_else(|| String::from("Unknown Packager")),
+            offline: layer.offline.unwrap_or(false),
+            locked: layer.locked.unwrap_or(false),
+            git_auth: layer.git_auth.unwrap_or_default(),
+        }
+    }
+
+    /// Database of installed packages, `var/lib/upkg` below the install root
+    /// unless configured explicitly.
+    pub fn db_path(&self) -> PathBuf {
+        match &self.db_path {
+            Some(db_path) => db_path.to_path_buf(),
+            None => self.install_root.join("var/lib/upkg"),
+        }
+    }
+
+    /// Build directory of the pkgbuild living in `pkgbuild_dir`: a `build`
+    /// directory next to it unless a `build_root` is configured.
+    pub fn build_dir_for(&self, pkgbuild_dir: &std::path::Path) -> PathBuf {
+        match &self.build_root {
+            Some(root) => match pkgbuild_dir.file_name() {
+                Some(name) => root.join(name),
+                None => root.to_path_buf(),
+            },
+            None => pkgbuild_dir.join("build"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upper_layer_wins() {
+        let mut layer: ConfigLayer = toml::from_str(
+            r#"
+            install_root = "/opt/root"
+            jobs = 4
+            cflags = "-O2"
+            "#,
+        )
+        .unwrap();
+        layer.merge(ConfigLayer {
+            jobs: Some(8),
+            packager: Some(String::from("Jane <jane@example.com>")),
+            ..Default::default()
+        });
+
+        let config = Config::from_layer(layer);
+        assert_eq!(config.install_root, PathBuf::from("/opt/root"));
+        assert_eq!(config.jobs, 8);
+        assert_eq!(config.cflags, "-O2");
+        assert_eq!(config.cxxflags, "-O2");
+        assert_eq!(config.makeflags, "-j8");
+        assert_eq!(config.packager, "Jane <jane@example.com>");
+    }
+
+    #[test]
+    fn test_git_auth_merges_by_host() {
+        let mut layer: ConfigLayer = toml::from_str(
+            r#"
+            [git_auth."github.com"]
+            token_env = "GITHUB_TOKEN"
+
+            [git_auth."git.example.com"]
+            ssh_key = "~/.ssh/id_ed25519"
+            "#,
+        )
+        .unwrap();
+        layer.merge(
+            toml::from_str(
+                r#"
+                [git_auth."github.com"]
+                ssh_agent = false
+                "#,
+            )
+            .unwrap(),
+        );
+
+        let config = Config::from_layer(layer);
+        assert_eq!(config.git_auth.len(), 2);
+        assert_eq!(config.git_auth["github.com"].ssh_agent, Some(false));
+        assert!(config.git_auth["github.com"].token_env.is_none());
+    }
+
+    #[test]
+    fn test_unknown_key_is_rejected() {
+        assert!(toml::from_str::<ConfigLayer>("instal_root = \"/\"").is_err());
+    }
+
+    #[test]
+    fn test_build_dir_for() {
+        let mut config = Config::from_layer(ConfigLayer::default());
+        let pkgbuild_dir = PathBuf::from("/pkgs/starship");
+        assert_eq!(
+            config.build_dir_for(&pkgbuild_dir),
+            PathBuf::from("/pkgs/starship/build")
+        );
+
+        config.build_root = Some(PathBuf::from("/var/tmp/upkg"));
+        assert_eq!(
+            config.build_dir_for(&pkgbuild_dir),
+            PathBuf::from("/var/tmp/upkg/starship")
+        );
+    }
+}
@@ -1,14 +1,42 @@
+use crate::lua::sandbox::*;
+use crate::lua::upkg_api::upkg_table;
+use crate::upkg::BuildEnv;
+use crate::upkg::extract_deps::extracted_dir;
 use crate::*;
 use mlua::prelude::*;
 
+/// A Lua state for a pkgbuild, see `sandbox` for what it can and can't do.
 pub fn create_lua_instance() -> LuaResult<Lua> {
     let lua = Lua::new();
+    deny_builtins(&lua)?;
+    lua.set_app_data(Phase::Metadata);
     lua_ok!(lua.sandbox(true));
 
     Ok(lua)
 }
 
-fn set_globals(lua: &Lua) -> LuaResult<()> {
+fn config_table(lua: &Lua, env: &BuildEnv) -> LuaResult<LuaTable> {
+    let config = &env.config;
+    let config_table = lua_ok!(lua.create_table(), "config table");
+
+    lua_ok!(config_table.set(
+        "install_root",
+        config.install_root.to_string_lossy().as_ref()
+    ));
+    lua_ok!(config_table.set("build_dir", env.build_dir.to_string_lossy().as_ref()));
+    lua_ok!(config_table.set("cache_dir", config.cache_dir.to_string_lossy().as_ref()));
+    lua_ok!(config_table.set("jobs", config.jobs));
+    lua_ok!(config_table.set("cflags", config.cflags.as_str()));
+    lua_ok!(config_table.set("cxxflags", config.cxxflags.as_str()));
+    lua_ok!(config_table.set("ldflags", config.ldflags.as_str()));
+    lua_ok!(config_table.set("makeflags", config.makeflags.as_str()));
+    lua_ok!(config_table.set("packager", config.packager.as_str()));
+    lua_ok!(config_table.set("offline", config.offline));
+
+    Ok(config_table)
+}
+
+fn set_globals(lua: &Lua, env: &BuildEnv) -> LuaResult<()> {
     lua_ok!(
         lua.globals()
             .set("Proto", lua_ok!(Proto::global_lua_value(lua))),
@@ -28,23 +56,251 @@ fn set_globals(lua: &Lua) -> LuaResult<()> {
     );
 
     lua_ok!(
-        lua.globals().set("InstallDir", LOCAL_INSTALL_PATH),
-        "setting global InstallDir failed"
+        lua.globals().set("Config", lua_ok!(config_table(lua, env))),
+        "setting global Config table failed"
+    );
+
+    lua_ok!(
+        lua.globals()
+            .set("PkgDir", env.pkg_dir.to_string_lossy().as_ref()),
+        "setting global PkgDir failed"
+    );
+
+    lua_ok!(
+        lua.globals().set("upkg", lua_ok!(upkg_table(lua, env))),
+        "setting global upkg table failed"
     );
 
     Ok(())
 }
 
-pub fn load_lua<ScriptPath: AsRef<Path>>(lua: &Lua, script_path: ScriptPath) -> LuaResult<()> {
-    let script_path_utf8 = script_path.as_ref().to_string_lossy();
-    let data = fs::read(script_path.as_ref()).map_err(lua_err_ctx!(script_path_utf8))?;
+// Local paths of `src`: `dir` for git checkouts and extracted archives, `file`
+// for downloads and files next to the pkgbuild, `path` is the first of both.
+fn source_table(lua: &Lua, src: &SourceField, env: &BuildEnv) -> LuaResult<LuaTable> {
+    let (dir, file) = match src.proto {
+        Proto::git => (
+            Some(git_clone::repo_dir(
+                &src.location,
+                &env.src_dir,
+                src.repo_name.as_deref(),
+            )),
+            None,
+        ),
+        Proto::url => {
+            let name = io_ok!(http_fetch::url_file_name(&src.location));
+            (extracted_dir(env, &name), Some(env.src_dir.join(name)))
+        }
+        Proto::file => (None, Some(env.pkgbuild_dir.join(&src.location))),
+    };
 
-    lua_ok!(set_globals(lua));
+    let source_table = lua_ok!(lua.create_table(), "source table");
+    lua_ok!(source_table.set("proto", lua_ok!(lua.to_value(&src.proto))));
+    lua_ok!(source_table.set("location", src.location.as_str()));
+    let path = dir
+        .as_ref()
+        .or(file.as_ref())
+        .map(|path| path.to_string_lossy());
+    lua_ok!(source_table.set("path", path.as_deref()));
+    let dir = dir.as_ref().map(|dir| dir.to_string_lossy());
+    lua_ok!(source_table.set("dir", dir.as_deref()));
+    let file = file.as_ref().map(|file| file.to_string_lossy());
+    lua_ok!(source_table.set("file", file.as_deref()));
 
-    lua_ok!(
-        lua.load(data).set_name(script_path_utf8.as_ref()).exec(),
-        script_path_utf8
-    );
+    Ok(source_table)
+}
+
+/// The table stage functions are called with: the directories of the build,
+/// the version, arch and job count, and the local paths of every source in
+/// the order of `Package.source`.
+pub fn stage_context(lua: &Lua, pkg: &Package, env: &BuildEnv) -> LuaResult<LuaTable> {
+    let ctx = lua_ok!(lua.create_table(), "stage context table");
+
+    lua_ok!(ctx.set("srcdir", env.src_dir.to_string_lossy().as_ref()));
+    lua_ok!(ctx.set("pkgdir", env.pkg_dir.to_string_lossy().as_ref()));
+    lua_ok!(ctx.set("builddir", env.build_dir.to_string_lossy().as_ref()));
+    lua_ok!(ctx.set("pkgname", pkg.pkg.name.as_str()));
+    lua_ok!(ctx.set("pkgver", pkg.pkg.ver.as_str()));
+    lua_ok!(ctx.set("pkgrel", pkg.pkg.rel()));
+    lua_ok!(ctx.set("arch", pkg.pkg.arch()));
+    lua_ok!(ctx.set("jobs", env.config.jobs));
+
+    let sources = lua_ok!(lua.create_table(), "sources table");
+    for src in &pkg.source.0 {
+        lua_ok!(sources.push(source_table(lua, src, env)?));
+    }
+    lua_ok!(ctx.set("sources", sources));
+
+    Ok(ctx)
+}
+
+pub fn load_lua(lua: &Lua, env: &BuildEnv) -> LuaResult<()> {
+    let script_path_utf8 = env.pkgbuild.to_string_lossy();
+    let data = fs::read(&env.pkgbuild).map_err(lua_err_ctx!(script_path_utf8))?;
+
+    lua_ok!(set_globals(lua, env));
+
+    // `@` makes Lua report errors as `<path>:<line>`
+    with_limits(lua, Limits::METADATA, &script_path_utf8, || {
+        lua.load(data)
+            .set_name(format!("@{}", script_path_utf8))
+            .exec()
+    })?;
 
     Ok(())
 }
+
+/// Calls the global stage function `stage` (e.g. `Build`) from the loaded pkgbuild
+/// with `ctx`, `work_dir` is where its commands run and its relative paths
+/// start. A pkgbuild that does not define the function simply skips the stage,
+/// reported by returning `false`.
+pub fn call_stage_fn<WorkDir: AsRef<Path>>(
+    lua: &Lua,
+    stage: &str,
+    work_dir: WorkDir,
+    ctx: &LuaTable,
+) -> LuaResult<bool> {
+    let stage_fn: Option<LuaFunction> = lua_ok!(
+        lua.globals().get(stage),
+        "global {} is not a function",
+        stage
+    );
+    let Some(stage_fn) = stage_fn else {
+        println!("{}() not defined, skipping", stage);
+        return Ok(false);
+    };
+
+    let work_dir = io_ok!(
+        std::path::absolute(work_dir.as_ref()),
+        work_dir.as_ref().to_string_lossy()
+    );
+    io_ok!(fs::create_dir_all(&work_dir), work_dir.to_string_lossy());
+
+    // xpcall keeps the stack alive long enough for debug.traceback to describe it
+    let runner = lua_ok!(
+        lua.load("local stage_fn, ctx = ...\nreturn xpcall(stage_fn, debug.traceback, ctx)")
+            .set_name("=upkg")
+            .into_function()
+    );
+    let result: LuaResult<(bool, LuaValue)> =
+        in_phase(lua, Phase::Stage(work_dir), || runner.call((stage_fn, ctx)));
+
+    match result {
+        Ok((true, _)) => Ok(true),
+        Ok((false, traceback)) => Err(LuaError::external(format!(
+            "[{}:{}] stage {}() failed:\n{}",
+            file!(),
+            line!(),
+            stage,
+            traceback
+                .to_string()
+                .unwrap_or_else(|_| format!("{:?}", traceback))
+        ))),
+        Err(err) => Err(LuaError::external(format!(
+            "[{}:{}] stage {}() failed:\n{}",
+            file!(),
+            line!(),
+            stage,
+            err
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+
+    #[test]
+    fn test_missing_stage_is_skipped() {
+        let lua = create_lua_instance().unwrap();
+        let ctx = lua.create_table().unwrap();
+        assert!(!call_stage_fn(&lua, "Build", std::env::temp_dir(), &ctx).unwrap());
+    }
+
+    #[test]
+    fn test_failing_stage_reports_traceback() {
+        let lua = create_lua_instance().unwrap();
+        lua.load("function Build()\n  error('boom')\nend")
+            .set_name("pkgbuild.lua")
+            .exec()
+            .unwrap();
+
+        let ctx = lua.create_table().unwrap();
+        let err = call_stage_fn(&lua, "Build", std::env::temp_dir(), &ctx)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("stage Build() failed"), "{}", err);
+        assert!(err.contains(":2: boom"), "{}", err);
+        assert!(err.contains("function Build"), "{}", err);
+    }
+
+    #[test]
+    fn test_stage_gets_context() {
+        let dir = scratch_dir("ctx");
+        fs::write(dir.join("pkgbuild.lua"), "").unwrap();
+        let env = BuildEnv::new(
+            dir.join("pkgbuild.lua"),
+            None,
+            crate::config::Config::from_layer(crate::config::ConfigLayer {
+                cache_dir: Some(dir.join("cache")),
+                jobs: Some(3),
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+        // the tarball is named after the tag, not the directory it unpacks into
+        fs::create_dir_all(&env.src_dir).unwrap();
+        let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(
+            fs::File::create(env.src_dir.join("v1.0.tar.gz")).unwrap(),
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "hello-1.0/README", &b"hello"[..])
+            .unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let lua = create_lua_instance().unwrap();
+        let pkg: Package = lua
+            .from_value(
+                lua.load(
+                    r#"return {
+                        pkg = { name = "hello", ver = "1.0", rel = 2, desc = "", arch = "any" },
+                        depends = {},
+                        source = {
+                            { proto = "git", url = "https://example.com/hello.git", repo_name = "src" },
+                            { proto = "url", url = "https://example.com/hello/v1.0.tar.gz" },
+                            { proto = "file", file = "fix.patch" },
+                        },
+                        checksum = {},
+                    }"#,
+                )
+                .eval()
+                .unwrap(),
+            )
+            .unwrap();
+        lua.load(
+            r#"function Build(ctx)
+                local git, url, file = ctx.sources[1], ctx.sources[2], ctx.sources[3]
+                assert(ctx.pkgver == "1.0" and ctx.pkgrel == 2 and ctx.arch == "any" and ctx.jobs == 3)
+                assert(git.proto == Proto.git and git.path == ctx.srcdir .. "/src" and git.file == nil)
+                assert(url.dir == ctx.srcdir .. "/hello-1.0" and url.path == url.dir)
+                assert(url.file == ctx.srcdir .. "/v1.0.tar.gz")
+                assert(file.dir == nil and file.path == file.file and file.file:sub(-9) == "fix.patch")
+                assert(ctx.pkgdir == ctx.builddir .. "/pkg")
+            end"#,
+        )
+        .set_name("pkgbuild.lua")
+        .exec()
+        .unwrap();
+        set_globals(&lua, &env).unwrap();
+        crate::upkg::extract_deps::extract(&pkg, &env).unwrap();
+
+        let ctx = stage_context(&lua, &pkg, &env).unwrap();
+        call_stage_fn(&lua, "Build", &env.src_dir, &ctx).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
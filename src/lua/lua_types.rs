@@ -1,4 +1,7 @@
 use crate::lua_err_ctx;
+use crate::proto::git_auth;
+use crate::sub_path::*;
+use crate::version::*;
 
 use mlua::prelude::*;
 use serde::{Deserialize, Serialize};
@@ -16,9 +19,27 @@ pub struct PkgInfo {
     #[serde(default)]
     pub rel: Option<u32>,
     pub desc: String,
+    #[serde(default)]
+    pub arch: Option<String>,
 }
 
-#[derive(EnumIter, Serialize, Deserialize, Debug)]
+impl PkgInfo {
+    pub fn rel(&self) -> u32 {
+        self.rel.unwrap_or(1)
+    }
+
+    /// Architecture the package is built for, the host one unless the pkgbuild
+    /// names one (e.g. `any` for architecture independent packages).
+    pub fn arch(&self) -> &str {
+        self.arch.as_deref().unwrap_or(std::env::consts::ARCH)
+    }
+
+    pub fn version(&self) -> Version {
+        Version::new(&self.ver, self.rel())
+    }
+}
+
+#[derive(EnumIter, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum Proto {
     git,
@@ -96,11 +117,15 @@ impl LuaGTableValue for CheckSumField {
 pub struct CheckSum(pub Vec<CheckSumField>);
 
 #[derive(Serialize, Deserialize, Debug)]
-#[serde(from = "CheckoutWrapper")]
+#[serde(try_from = "CheckoutWrapper")]
 #[allow(non_camel_case_types)]
 pub enum CheckoutType {
     tag(String),
     branch(String),
+    /// Full (or already fetched abbreviated) commit id, checked out detached.
+    commit(String),
+    /// Any revspec git understands, e.g. `v1.2~3`, checked out detached.
+    rev(String),
     none,
 }
 
@@ -108,15 +133,59 @@ pub enum CheckoutType {
 struct CheckoutWrapper {
     tag: Option<String>,
     branch: Option<String>,
+    commit: Option<String>,
+    rev: Option<String>,
+}
+
+/// Whether `id` looks like a (possibly abbreviated) commit id.
+pub fn is_commit_id(id: &str) -> bool {
+    (7..=40).contains(&id.len()) && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+impl TryFrom<CheckoutWrapper> for CheckoutType {
+    type Error = String;
+
+    fn try_from(wrapper: CheckoutWrapper) -> Result<CheckoutType, String> {
+        match (wrapper.tag, wrapper.branch, wrapper.commit, wrapper.rev) {
+            (Some(t), None, None, None) => Ok(CheckoutType::tag(t)),
+            (None, Some(b), None, None) => Ok(CheckoutType::branch(b)),
+            (None, None, Some(c), None) if is_commit_id(&c) => Ok(CheckoutType::commit(c)),
+            (None, None, Some(c), None) => Err(format!(
+                "commit is not a commit id (7 to 40 hex digits): {}",
+                c
+            )),
+            (None, None, None, Some(r)) => Ok(CheckoutType::rev(r)),
+            (None, None, None, None) => Ok(CheckoutType::none),
+            _ => Err(String::from(
+                "only one of tag, branch, commit or rev can be given",
+            )),
+        }
+    }
+}
+
+/// Submodules of a git source to init and update: `true` for all of them, or
+/// a list of paths.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Submodules {
+    All(bool),
+    Paths(Vec<String>),
 }
 
-impl From<CheckoutWrapper> for CheckoutType {
-    fn from(wrapper: CheckoutWrapper) -> CheckoutType {
-        match (wrapper.tag, wrapper.branch) {
-            (Some(t), None) => CheckoutType::tag(t),
-            (None, Some(b)) => CheckoutType::branch(b),
-            (None, None) => CheckoutType::none,
-            (Some(_), Some(_)) => panic!("Cannot specify both tag and branch"),
+impl Default for Submodules {
+    fn default() -> Self {
+        Submodules::All(false)
+    }
+}
+
+impl Submodules {
+    /// Whether the submodule at `path` gets updated.
+    pub fn includes(&self, path: &str) -> bool {
+        match self {
+            Submodules::All(all) => *all,
+            Submodules::Paths(paths) => paths
+                .iter()
+                .any(|sm_path| sm_path.trim_end_matches('/') == path),
         }
     }
 }
@@ -133,13 +202,67 @@ pub struct SourceField {
 
     #[serde(default)]
     pub repo_name: Option<String>,
+
+    /// Commit id (or an unambiguous prefix of it) HEAD of a git source must be at.
+    #[serde(default)]
+    pub pin: Option<String>,
+
+    /// Check out the newest tag of the repo instead of `tag` once there is a
+    /// later version than `tag`.
+    #[serde(default)]
+    pub follow_latest: bool,
+
+    /// Only fetch the last `depth` commits of a git source.
+    #[serde(default)]
+    pub depth: Option<u32>,
+
+    /// Only fetch the tag or branch that gets checked out.
+    #[serde(default)]
+    pub single_branch: bool,
+
+    /// Partial clone filter (e.g. `blob:none`). libgit2 can't do partial
+    /// clones, so `validate` rejects it rather than silently fetching it all.
+    #[serde(default)]
+    pub filter: Option<String>,
+
+    /// Submodules to init and update recursively after the checkout.
+    #[serde(default)]
+    pub submodules: Submodules,
+
+    /// Credentials for a private remote, overriding those configured for its host.
+    #[serde(default)]
+    pub auth: Option<git_auth::GitAuth>,
+
+    /// Whether a file source is a patch applied after extraction, defaults to
+    /// files ending in `.patch` or `.diff`.
+    #[serde(default)]
+    pub patch: Option<bool>,
+
+    /// Directory below the src dir a patch is applied in [default: the src dir].
+    #[serde(default)]
+    pub apply_to: Option<String>,
+
+    /// Leading components stripped from the paths in a patch, like `patch -p`
+    /// [default: 1].
+    #[serde(default)]
+    pub strip: Option<u32>,
+}
+
+impl SourceField {
+    /// Whether this is a file source `upkg` applies as a patch.
+    pub fn is_patch(&self) -> bool {
+        self.proto == Proto::file
+            && self.patch.unwrap_or_else(|| {
+                self.location.ends_with(".patch") || self.location.ends_with(".diff")
+            })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(transparent)]
 pub struct Source(pub Vec<SourceField>);
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum DepInfo {
     Full {
@@ -154,6 +277,47 @@ pub enum DepInfo {
     Simple(String),
 }
 
+impl DepInfo {
+    pub fn name(&self) -> &str {
+        match self {
+            DepInfo::Full { name, .. } => name,
+            DepInfo::Simple(dep) => match constraint_start(dep) {
+                Some(start) => dep[..start].trim(),
+                None => dep,
+            },
+        }
+    }
+
+    /// Parses `"glibc>=2.38"` or `{ name = "rust", ver = ">=1.80,<2" }`, a `rel`
+    /// applies to every version of `ver` that doesn't name a release itself.
+    pub fn dependency(&self) -> std::io::Result<Dependency> {
+        match self {
+            DepInfo::Simple(dep) => Dependency::parse(dep),
+            DepInfo::Full { name, ver, rel, .. } => {
+                let mut dep = Dependency::with_req(name, ver.as_deref().unwrap_or_default())?;
+                if let Some(rel) = rel {
+                    for constraint in &mut dep.req.0 {
+                        constraint
+                            .version
+                            .rel
+                            .get_or_insert_with(|| rel.to_string());
+                    }
+                }
+                Ok(dep)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for DepInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.dependency() {
+            Ok(dep) => write!(f, "{}", dep),
+            Err(_) => write!(f, "{}", self.name()),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Package {
     pub pkg: PkgInfo,
@@ -184,3 +348,185 @@ pub struct Package {
     pub source: Source,
     pub checksum: CheckSum,
 }
+
+impl Package {
+    /// Every dependency like list of the package, labeled with its field name.
+    pub fn dep_lists(&self) -> [(&'static str, &[DepInfo]); 7] {
+        [
+            ("provides", &self.provides),
+            ("depends", &self.depends),
+            ("opt_depends", &self.opt_depends),
+            ("check_depends", &self.check_depends),
+            ("make_depends", &self.make_depends),
+            ("conflicts", &self.conflicts),
+            ("replaces", &self.replaces),
+        ]
+    }
+
+    /// Checks the package version and all version constraints, so a typo shows
+    /// up when the pkgbuild is loaded rather than halfway through a build.
+    pub fn validate(&self) -> std::io::Result<()> {
+        if self.pkg.ver.contains('-') {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "[{}:{}] pkg.ver may not contain '-', use pkg.rel for the release: {}",
+                    file!(),
+                    line!(),
+                    self.pkg.ver
+                ),
+            ));
+        }
+        Version::parse(&self.pkg.ver)?;
+
+        for src in &self.source.0 {
+            if src.follow_latest && !matches!(src.checkout, CheckoutType::tag(_)) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "[{}:{}] follow_latest of {} needs a tag to start from",
+                        file!(),
+                        line!(),
+                        src.location
+                    ),
+                ));
+            }
+            if git_auth::has_password(&src.location) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "[{}:{}] source urls may not carry a password, use auth.token_env: {}",
+                        file!(),
+                        line!(),
+                        git_auth::url_host(&src.location).unwrap_or_default()
+                    ),
+                ));
+            }
+            if src.depth == Some(0) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "[{}:{}] depth of {} has to be at least 1",
+                        file!(),
+                        line!(),
+                        src.location
+                    ),
+                ));
+            }
+            if let Some(filter) = &src.filter {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!(
+                        "[{}:{}] filter of {} is not supported, libgit2 has no partial clones: {}",
+                        file!(),
+                        line!(),
+                        src.location,
+                        filter
+                    ),
+                ));
+            }
+            if (src.apply_to.is_some() || src.strip.is_some()) && !src.is_patch() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "[{}:{}] apply_to and strip only apply to patches, set patch = true: {}",
+                        file!(),
+                        line!(),
+                        src.location
+                    ),
+                ));
+            }
+            if let Some(apply_to) = &src.apply_to
+                && (Path::new(apply_to).is_absolute()
+                    || !Path::new("src").join(apply_to).is_lexical_subpath_of("src"))
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "[{}:{}] apply_to of {} has to be a directory inside the src dir: {}",
+                        file!(),
+                        line!(),
+                        src.location,
+                        apply_to
+                    ),
+                ));
+            }
+            if let Some(pin) = &src.pin
+                && !is_commit_id(pin)
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "[{}:{}] pin of {} is not a commit id (7 to 40 hex digits): {}",
+                        file!(),
+                        line!(),
+                        src.location,
+                        pin
+                    ),
+                ));
+            }
+        }
+
+        for (field, deps) in self.dep_lists() {
+            for dep in deps {
+                dep.dependency().map_err(|err| {
+                    std::io::Error::new(err.kind(), format!("{}: {}", field, err))
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(lua_src: &str) -> LuaResult<SourceField> {
+        let lua = Lua::new();
+        lua.from_value(lua.load(lua_src).eval()?)
+    }
+
+    #[test]
+    fn test_checkout_options() {
+        let src = source(r#"return { proto = "git", url = "u", commit = "0123abcd" }"#).unwrap();
+        assert!(matches!(src.checkout, CheckoutType::commit(ref id) if id == "0123abcd"));
+        let src = source(r#"return { proto = "git", url = "u", rev = "v1.2~3" }"#).unwrap();
+        assert!(matches!(src.checkout, CheckoutType::rev(ref rev) if rev == "v1.2~3"));
+
+        let err = source(r#"return { proto = "git", url = "u", tag = "v1", branch = "main" }"#)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("only one of tag, branch, commit or rev"),
+            "{}",
+            err
+        );
+        assert!(source(r#"return { proto = "git", url = "u", commit = "main" }"#).is_err());
+    }
+
+    #[test]
+    fn test_filter_is_rejected() {
+        let lua = Lua::new();
+        let pkg: Package = lua
+            .from_value(
+                lua.load(
+                    r#"return {
+                        pkg = { name = "hello", ver = "1.0", desc = "" },
+                        depends = {},
+                        source = {
+                            { proto = "git", url = "https://example.com/hello.git", filter = "blob:none" },
+                        },
+                        checksum = {},
+                    }"#,
+                )
+                .eval()
+                .unwrap(),
+            )
+            .unwrap();
+        let err = pkg.validate().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("is not supported"), "{}", err);
+    }
+}
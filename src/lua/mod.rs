@@ -1,2 +1,5 @@
 pub mod load_lua;
 pub mod lua_types;
+pub mod sandbox;
+pub mod upkg_api;
+pub mod upkg_fs;
@@ -0,0 +1,252 @@
+use crate::*;
+use mlua::prelude::*;
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// What a pkgbuild may do right now. Its top level only describes the package
+/// and gets no access to processes or files, the stage functions
+/// (`Prepare`, `Build`, `Check`, `Install`) get the `upkg` APIs as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Phase {
+    Metadata,
+    /// A stage function running in this directory, relative paths passed to
+    /// the `upkg` APIs start from it.
+    Stage(PathBuf),
+}
+
+/// Bounds on evaluating the top level of a pkgbuild.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub time: Duration,
+    pub memory: usize,
+}
+
+impl Limits {
+    pub const METADATA: Limits = Limits {
+        time: Duration::from_secs(10),
+        memory: 64 * 1024 * 1024,
+    };
+}
+
+// Builtins a pkgbuild might reach for and what to use instead, `None` being
+// the global table.
+const DENIED: [(Option<&str>, &str, &str); 15] = [
+    (Some("io"), "popen", "use upkg.run"),
+    (Some("io"), "open", "use the upkg fs helpers"),
+    (Some("io"), "lines", "use the upkg fs helpers"),
+    (Some("io"), "read", "use the upkg fs helpers"),
+    (Some("io"), "write", "use print or upkg.write_file"),
+    (Some("io"), "input", "use the upkg fs helpers"),
+    (Some("io"), "output", "use the upkg fs helpers"),
+    (Some("os"), "execute", "use upkg.run"),
+    (Some("os"), "remove", "use the upkg fs helpers"),
+    (Some("os"), "rename", "use the upkg fs helpers"),
+    (Some("os"), "tmpname", "use a path below ctx.builddir"),
+    (Some("os"), "getenv", "use Config or the env of upkg.run"),
+    (Some("os"), "exit", "raise an error instead"),
+    (None, "require", "a pkgbuild is a single file"),
+    (None, "dofile", "a pkgbuild is a single file"),
+];
+
+/// `pkgbuild.lua:12` for the Lua code `level` frames up the stack, 1 being
+/// the caller of a Rust function.
+pub fn lua_location(lua: &Lua, level: usize) -> String {
+    lua.inspect_stack(level, |debug| {
+        let source = debug.source();
+        format!(
+            "{}:{}",
+            source.short_src.as_deref().unwrap_or("?"),
+            debug
+                .current_line()
+                .map_or_else(|| String::from("?"), |line| line.to_string())
+        )
+    })
+    .unwrap_or_else(|| String::from("pkgbuild"))
+}
+
+fn denied_fn(lua: &Lua, name: String, hint: &'static str) -> LuaResult<LuaFunction> {
+    lua.create_function(move |lua, _: LuaMultiValue| -> LuaResult<()> {
+        Err(LuaError::external(format!(
+            "[{}:{}] {}: {} is not available to pkgbuilds, {}",
+            file!(),
+            line!(),
+            lua_location(lua, 1),
+            name,
+            hint
+        )))
+    })
+}
+
+/// Replaces the builtins in `DENIED` with functions explaining what to use
+/// instead, has to run before the globals are sandboxed.
+pub fn deny_builtins(lua: &Lua) -> LuaResult<()> {
+    let globals = lua.globals();
+    for (table, func, hint) in DENIED {
+        let (table, name) = match table {
+            Some(table) => {
+                let lib = match lua_ok!(globals.get::<Option<LuaTable>>(table)) {
+                    Some(lib) => lib,
+                    None => {
+                        let lib = lua_ok!(lua.create_table());
+                        lua_ok!(globals.set(table, &lib));
+                        lib
+                    }
+                };
+                (lib, format!("{}.{}", table, func))
+            }
+            None => (globals.clone(), func.to_string()),
+        };
+        lua_ok!(table.set(func, lua_ok!(denied_fn(lua, name, hint))));
+    }
+    Ok(())
+}
+
+/// The working directory of the running stage function, errors outside of
+/// one. `func` names the API for the message.
+pub fn require_stage(lua: &Lua, func: &str) -> LuaResult<PathBuf> {
+    match lua.app_data_ref::<Phase>().as_deref() {
+        Some(Phase::Stage(work_dir)) => Ok(work_dir.clone()),
+        _ => Err(LuaError::external(format!(
+            "[{}:{}] {}: {} can only be used in Prepare, Build, Check and Install",
+            file!(),
+            line!(),
+            lua_location(lua, 1),
+            func
+        ))),
+    }
+}
+
+/// Runs `f` in `phase`, switching back to the metadata phase afterwards.
+pub fn in_phase<R>(lua: &Lua, phase: Phase, f: impl FnOnce() -> R) -> R {
+    lua.set_app_data(phase);
+    let result = f();
+    lua.set_app_data(Phase::Metadata);
+    result
+}
+
+/// Runs `f` with the memory and time of the Lua state bounded by `limits`,
+/// `name` is the pkgbuild the errors mention.
+pub fn with_limits<R>(
+    lua: &Lua,
+    limits: Limits,
+    name: &str,
+    f: impl FnOnce() -> LuaResult<R>,
+) -> LuaResult<R> {
+    let prev_limit = lua_ok!(lua.set_memory_limit(limits.memory));
+    let deadline = Instant::now() + limits.time;
+    let time = limits.time;
+    lua.set_interrupt(move |lua| {
+        if Instant::now() < deadline {
+            return Ok(LuaVmState::Continue);
+        }
+        Err(LuaError::external(format!(
+            "[{}:{}] {}: evaluating the pkgbuild took longer than {:?}",
+            file!(),
+            line!(),
+            lua_location(lua, 0),
+            time
+        )))
+    });
+
+    let result = f();
+
+    lua.remove_interrupt();
+    lua_ok!(lua.set_memory_limit(prev_limit));
+
+    result.map_err(|err| match err {
+        LuaError::MemoryError(_) => LuaError::external(format!(
+            "[{}:{}] {}: evaluating the pkgbuild used more than {} MiB of memory",
+            file!(),
+            line!(),
+            name,
+            limits.memory / (1024 * 1024)
+        )),
+        err => err,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandboxed() -> Lua {
+        let lua = Lua::new();
+        deny_builtins(&lua).unwrap();
+        lua.set_app_data(Phase::Metadata);
+        lua.sandbox(true).unwrap();
+        lua
+    }
+
+    #[test]
+    fn test_denied_builtins_name_the_line() {
+        let lua = sandboxed();
+        let err = lua
+            .load("local pkg = {}\nlocal out = io.popen('ls')")
+            .set_name("@/pkgs/hello/pkgbuild.lua")
+            .exec()
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(
+                "/pkgs/hello/pkgbuild.lua:2: io.popen is not available to pkgbuilds, use upkg.run"
+            ),
+            "{}",
+            err
+        );
+        let err = lua
+            .load("os.execute('make')")
+            .set_name("@pkgbuild.lua")
+            .exec()
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("pkgbuild.lua:1: os.execute is not available"),
+            "{}",
+            err
+        );
+        assert!(lua.load("require('other')").exec().is_err());
+        // what is left of os stays usable
+        assert!(lua.load("return os.time()").eval::<i64>().is_ok());
+    }
+
+    #[test]
+    fn test_limits_stop_runaway_pkgbuilds() {
+        let lua = sandboxed();
+        let limits = Limits {
+            time: Duration::from_millis(100),
+            memory: 16 * 1024 * 1024,
+        };
+
+        let err = with_limits(&lua, limits, "pkgbuild.lua", || {
+            lua.load("local n = 0\nwhile true do\n  n = n + 1\nend")
+                .set_name("@pkgbuild.lua")
+                .exec()
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("pkgbuild.lua:"), "{}", err);
+        assert!(err.contains("took longer than 100ms"), "{}", err);
+
+        let limits = Limits {
+            time: Duration::from_secs(60),
+            ..limits
+        };
+        let err = with_limits(&lua, limits, "pkgbuild.lua", || {
+            lua.load("local t = {}\nfor i = 1, 1e8 do\n  t[i] = string.rep('x', 64) .. i\nend")
+                .exec()
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("pkgbuild.lua: evaluating the pkgbuild used more than 16 MiB"),
+            "{}",
+            err
+        );
+
+        // the limits are gone afterwards
+        lua.load("local t = {}\nfor i = 1, 1e5 do\n  t[i] = string.rep('x', 256) .. i\nend")
+            .exec()
+            .unwrap();
+    }
+}
@@ -0,0 +1,313 @@
+use crate::lua::sandbox::require_stage;
+use crate::lua::upkg_fs::{FsRoots, set_fs_fns};
+use crate::upkg::BuildEnv;
+use crate::*;
+use mlua::prelude::*;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+
+/// Arguments of `upkg.run{...}`. `cmd` is executed directly with `args`,
+/// only `shell = true` hands it to `sh -c` (`args` become `$1`, `$2`, ...).
+/// Commands run in the stage working directory unless `cwd` says otherwise.
+/// Only `cwd` is checked against the build directories, the command itself
+/// runs with every right of the user running upkg.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RunSpec {
+    cmd: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    cwd: Option<PathBuf>,
+    #[serde(default)]
+    shell: bool,
+}
+
+impl RunSpec {
+    fn command_line(&self) -> String {
+        std::iter::once(self.cmd.as_str())
+            .chain(self.args.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+struct RunOutput {
+    status: ExitStatus,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+// Copies `from` to the terminal and the build log as it arrives and keeps a
+// copy for the caller.
+fn tee<R: Read, W: Write>(mut from: R, mut term: W, log: &Mutex<File>) -> std::io::Result<Vec<u8>> {
+    let mut captured = Vec::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        let read = from.read(&mut buf)?;
+        if read == 0 {
+            return Ok(captured);
+        }
+        let chunk = &buf[..read];
+        // the terminal going away must not kill the build
+        let _ = term.write_all(chunk).and_then(|_| term.flush());
+        log.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .write_all(chunk)?;
+        captured.extend_from_slice(chunk);
+    }
+}
+
+fn run(
+    spec: &RunSpec,
+    cwd: &Path,
+    defaults: &HashMap<String, String>,
+    log_path: &Path,
+) -> std::io::Result<RunOutput> {
+    let mut command = if spec.shell {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(&spec.cmd).arg("sh");
+        command
+    } else if spec.cmd.contains('/') {
+        // `./configure` means the one in `cwd`, not in our own working dir
+        Command::new(cwd.join(&spec.cmd))
+    } else {
+        Command::new(&spec.cmd)
+    };
+    command
+        .args(&spec.args)
+        .envs(defaults)
+        .envs(&spec.env)
+        .current_dir(cwd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let mut log = io_ok!(
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path),
+        log_path.to_string_lossy()
+    );
+    let header = format!("==> [{}] {}", cwd.to_string_lossy(), spec.command_line());
+    println!("{}", header);
+    io_ok!(writeln!(log, "{}", header));
+
+    let mut child = io_ok!(command.spawn(), "spawning {}", spec.cmd);
+    // both pipes are set up above
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let log = Mutex::new(log);
+
+    let (stdout, stderr) = std::thread::scope(|scope| {
+        let out = scope.spawn(|| tee(stdout, std::io::stdout(), &log));
+        let err = tee(stderr, std::io::stderr(), &log);
+        // tee never panics, a failed join only means a broken pipe
+        (out.join().unwrap(), err)
+    });
+    let status = io_ok!(child.wait(), "waiting for {}", spec.cmd);
+
+    Ok(RunOutput {
+        status,
+        stdout: io_ok!(stdout),
+        stderr: io_ok!(stderr),
+    })
+}
+
+fn output_table(lua: &Lua, output: RunOutput) -> LuaResult<LuaTable> {
+    let table = lua_ok!(lua.create_table());
+    lua_ok!(table.set("status", output.status.code()));
+    lua_ok!(table.set("success", output.status.success()));
+    lua_ok!(table.set("stdout", lua_ok!(lua.create_string(&output.stdout))));
+    lua_ok!(table.set("stderr", lua_ok!(lua.create_string(&output.stderr))));
+    Ok(table)
+}
+
+// Build flags from the config every command starts out with.
+fn default_env(env: &BuildEnv) -> HashMap<String, String> {
+    let config = &env.config;
+    HashMap::from([
+        (String::from("CFLAGS"), config.cflags.clone()),
+        (String::from("CXXFLAGS"), config.cxxflags.clone()),
+        (String::from("LDFLAGS"), config.ldflags.clone()),
+        (String::from("MAKEFLAGS"), config.makeflags.clone()),
+    ])
+}
+
+/// Path of the log every command run by a pkgbuild is appended to.
+pub fn build_log(env: &BuildEnv) -> PathBuf {
+    env.build_dir.join("build.log")
+}
+
+/// The `upkg` global, native helpers for pkgbuild stage functions.
+pub fn upkg_table(lua: &Lua, env: &BuildEnv) -> LuaResult<LuaTable> {
+    let table = lua_ok!(lua.create_table(), "upkg table");
+    let defaults = default_env(env);
+    let log_path = build_log(env);
+
+    let roots = FsRoots::new(env);
+
+    let spawn = move |lua: &Lua, func: &str, spec: LuaValue| -> LuaResult<(RunSpec, RunOutput)> {
+        let work_dir = require_stage(lua, func)?;
+        let spec: RunSpec = lua.from_value(spec)?;
+        // commands start out in the directories the fs helpers may touch
+        let cwd = match &spec.cwd {
+            Some(cwd) => roots.in_dir(work_dir).readable("run", cwd)?,
+            None => work_dir,
+        };
+        if let Some(dir) = log_path.parent() {
+            io_ok!(fs::create_dir_all(dir), dir.to_string_lossy());
+        }
+        let output = io_ok!(run(&spec, &cwd, &defaults, &log_path));
+        Ok((spec, output))
+    };
+    let spawn = std::rc::Rc::new(spawn);
+
+    let run_fn = {
+        let spawn = spawn.clone();
+        lua_ok!(lua.create_function(move |lua, spec: LuaValue| {
+            let (_, output) = spawn(lua, "upkg.run", spec)?;
+            output_table(lua, output)
+        }))
+    };
+    lua_ok!(table.set("run", run_fn));
+
+    let run_checked_fn = lua_ok!(lua.create_function(move |lua, spec: LuaValue| {
+        let (spec, output) = spawn(lua, "upkg.run_checked", spec)?;
+        if !output.status.success() {
+            return Err(LuaError::RuntimeError(format!(
+                "`{}` failed with {}",
+                spec.command_line(),
+                output.status
+            )));
+        }
+        output_table(lua, output)
+    }));
+    lua_ok!(table.set("run_checked", run_checked_fn));
+
+    lua_ok!(set_fs_fns(lua, &table, env));
+
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::lua::sandbox::Phase;
+    use crate::test_util::scratch_dir;
+
+    #[test]
+    fn test_run_captures_output_and_checks_status() {
+        let dir = scratch_dir("run");
+        fs::write(dir.join("pkgbuild.lua"), "").unwrap();
+        let env = BuildEnv::new(
+            dir.join("pkgbuild.lua"),
+            Some(dir.join("build")),
+            Config::from_layer(ConfigLayer {
+                cflags: Some(String::from("-O1")),
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+
+        let lua = Lua::new();
+        lua.globals()
+            .set("upkg", upkg_table(&lua, &env).unwrap())
+            .unwrap();
+        let err = lua
+            .load(r#"upkg.run{ cmd = "true" }"#)
+            .set_name("@pkgbuild.lua")
+            .exec()
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(
+                "pkgbuild.lua:1: upkg.run can only be used in Prepare, Build, Check and Install"
+            ),
+            "{}",
+            err
+        );
+        fs::create_dir_all(&env.src_dir).unwrap();
+        lua.set_app_data(Phase::Stage(env.src_dir.clone()));
+        assert!(
+            lua.load(r#"upkg.run{ cmd = "true", cwd = "/" }"#)
+                .exec()
+                .is_err()
+        );
+
+        // relative commands and dirs start at the stage working directory
+        fs::create_dir_all(env.src_dir.join("sub")).unwrap();
+        fs::write(env.src_dir.join("sub/hello.sh"), "#!/bin/sh\npwd\n").unwrap();
+        fs::set_permissions(
+            env.src_dir.join("sub/hello.sh"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )
+        .unwrap();
+        let (pwd, hello): (String, String) = lua
+            .load(
+                r#"return upkg.run_checked{ cmd = "pwd" }.stdout,
+                    upkg.run_checked{ cmd = "./hello.sh", cwd = "sub" }.stdout"#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(pwd, format!("{}\n", env.src_dir.to_string_lossy()));
+        assert_eq!(
+            hello,
+            format!("{}\n", env.src_dir.join("sub").to_string_lossy())
+        );
+
+        let (status, stdout): (i32, String) = lua
+            .load(
+                r#"local out = upkg.run{ cmd = "echo", args = { "a;b", "$CFLAGS" } }
+                return out.status, out.stdout"#,
+            )
+            .eval()
+            .unwrap();
+        // no shell, so neither `;` nor `$` mean anything
+        assert_eq!((status, stdout.as_str()), (0, "a;b $CFLAGS\n"));
+
+        let stderr: String = lua
+            .load(
+                r#"local cmd = "echo $CFLAGS $X >&2"
+                return upkg.run_checked{ cmd = cmd, env = { X = "x" }, shell = true }.stderr"#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(stderr, "-O1 x\n");
+
+        let (status, success): (i32, bool) = lua
+            .load(
+                r#"local out = upkg.run{ cmd = "false" }
+                return out.status, out.success"#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!((status, success), (1, false));
+
+        let err = lua
+            .load(r#"upkg.run_checked{ cmd = "sh", args = { "-c", "exit 3" } }"#)
+            .exec()
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("`sh -c exit 3` failed with exit status: 3"),
+            "{}",
+            err
+        );
+
+        let log = fs::read_to_string(build_log(&env)).unwrap();
+        assert!(log.contains("] echo a;b $CFLAGS\na;b $CFLAGS\n"), "{}", log);
+        assert!(log.contains("-O1 x\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,515 @@
+use crate::lua::sandbox::require_stage;
+use crate::upkg::BuildEnv;
+use crate::*;
+use mlua::prelude::*;
+
+use regex::Regex;
+
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, PathBuf};
+
+/// Directories the fs helpers of the `upkg` global may touch. Everything is
+/// written below the build dir (which holds `src_dir` and `pkg_dir`), files
+/// shipped next to the pkgbuild can be read as well. Relative paths start at
+/// `cwd`.
+#[derive(Debug, Clone)]
+pub struct FsRoots {
+    write: Vec<PathBuf>,
+    read: Vec<PathBuf>,
+    cwd: PathBuf,
+}
+
+fn outside_err(func: &str, path: &Path) -> Error {
+    Error::new(
+        ErrorKind::PermissionDenied,
+        format!(
+            "[{}:{}] upkg.{}: {} is outside of the build directories",
+            file!(),
+            line!(),
+            func,
+            path.to_string_lossy()
+        ),
+    )
+}
+
+fn parse_mode(mode: Option<&str>, default: u32) -> std::io::Result<u32> {
+    match mode {
+        Some(mode) => u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "[{}:{}] invalid mode {:?}, expected octal digits like \"755\"",
+                        file!(),
+                        line!(),
+                        mode
+                    ),
+                )
+            }),
+        None => Ok(default),
+    }
+}
+
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    io_ok!(
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)),
+        path.to_string_lossy()
+    );
+    Ok(())
+}
+
+impl FsRoots {
+    pub fn new(env: &BuildEnv) -> FsRoots {
+        let write = vec![env.build_dir.clone(), env.pkg_dir.clone()];
+        let mut read = write.clone();
+        read.push(env.pkgbuild_dir.clone());
+        FsRoots {
+            write,
+            read,
+            cwd: env.src_dir.clone(),
+        }
+    }
+
+    /// The same roots with relative paths starting at `cwd`.
+    pub fn in_dir(&self, cwd: PathBuf) -> FsRoots {
+        FsRoots {
+            cwd,
+            ..self.clone()
+        }
+    }
+
+    // `path` made absolute against `cwd`, not resolved
+    fn absolute(&self, path: &Path) -> std::io::Result<PathBuf> {
+        let path = self.cwd.join(path);
+        Ok(io_ok!(std::path::absolute(&path), path.to_string_lossy()))
+    }
+
+    fn inside(roots: &[PathBuf], path: &Path) -> std::io::Result<bool> {
+        for root in roots {
+            if root.exists() && path.is_subpath_of(root)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// An existing file or directory below one of the read roots, symlinks
+    /// resolved.
+    pub fn readable(&self, func: &str, path: &Path) -> std::io::Result<PathBuf> {
+        let path = self.absolute(path)?;
+        if !Self::inside(&self.read, &path)? {
+            return Err(outside_err(func, &path));
+        }
+        Ok(path)
+    }
+
+    /// Creates `dir` below one of the write roots. The deepest existing
+    /// ancestor is checked before anything is created so a symlink can't
+    /// redirect `create_dir_all` elsewhere.
+    pub fn create_dir(&self, func: &str, dir: &Path) -> std::io::Result<PathBuf> {
+        let dir = self.absolute(dir)?;
+        if !self
+            .write
+            .iter()
+            .any(|root| dir.is_lexical_subpath_of(root))
+        {
+            return Err(outside_err(func, &dir));
+        }
+
+        // the root of the filesystem always exists
+        let existing = dir.ancestors().find(|dir| dir.exists()).unwrap();
+        if !Self::inside(&self.write, existing)? {
+            return Err(outside_err(func, &dir));
+        }
+        io_ok!(fs::create_dir_all(&dir), dir.to_string_lossy());
+        if !Self::inside(&self.write, &dir)? {
+            return Err(outside_err(func, &dir));
+        }
+        Ok(dir)
+    }
+
+    /// A file path below one of the write roots with its parent created. A
+    /// symlink already at `path` is removed rather than written through.
+    pub fn writable(&self, func: &str, path: &Path) -> std::io::Result<PathBuf> {
+        let path = self.absolute(path)?;
+        let Some(parent) = path.parent() else {
+            return Err(outside_err(func, &path));
+        };
+        let parent = self.create_dir(func, parent)?;
+        // `parent` was checked, the file name must not climb out of it again
+        let path = match path.file_name() {
+            Some(name) => parent.join(name),
+            None => return Err(outside_err(func, &path)),
+        };
+
+        if let Ok(meta) = fs::symlink_metadata(&path)
+            && meta.file_type().is_symlink()
+        {
+            io_ok!(fs::remove_file(&path), path.to_string_lossy());
+        }
+        Ok(path)
+    }
+}
+
+/// Copies `src` to `dest` with `mode`, into `dest` if it is a directory or
+/// ends with a `/`.
+pub fn install_file(
+    roots: &FsRoots,
+    src: &Path,
+    dest: &str,
+    mode: Option<&str>,
+) -> std::io::Result<PathBuf> {
+    let mode = parse_mode(mode, 0o644)?;
+    let src = roots.readable("install_file", src)?;
+    if !src.is_file() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "[{}:{}] upkg.install_file: not a file: {}",
+                file!(),
+                line!(),
+                src.to_string_lossy()
+            ),
+        ));
+    }
+
+    let into_dir = dest.ends_with('/');
+    let mut dest = roots.absolute(Path::new(dest))?;
+    if into_dir || dest.is_dir() {
+        // a file always has a name
+        dest.push(src.file_name().unwrap());
+    }
+    let dest = roots.writable("install_file", &dest)?;
+
+    io_ok!(
+        fs::copy(&src, &dest),
+        "{} -> {}",
+        src.to_string_lossy(),
+        dest.to_string_lossy()
+    );
+    set_mode(&dest, mode)?;
+    Ok(dest)
+}
+
+pub fn install_dir(roots: &FsRoots, dir: &Path, mode: Option<&str>) -> std::io::Result<PathBuf> {
+    let mode = parse_mode(mode, 0o755)?;
+    let dir = roots.create_dir("install_dir", dir)?;
+    set_mode(&dir, mode)?;
+    Ok(dir)
+}
+
+pub fn write_file(
+    roots: &FsRoots,
+    path: &Path,
+    contents: &[u8],
+    mode: Option<&str>,
+) -> std::io::Result<PathBuf> {
+    let mode = parse_mode(mode, 0o644)?;
+    let path = roots.writable("write_file", path)?;
+    io_ok!(fs::write(&path, contents), path.to_string_lossy());
+    set_mode(&path, mode)?;
+    Ok(path)
+}
+
+/// Creates the symlink `link` pointing at `target`, replacing an older
+/// symlink. `target` is stored as given, it usually points into the system
+/// the package gets installed to.
+pub fn symlink(roots: &FsRoots, target: &Path, link: &Path) -> std::io::Result<PathBuf> {
+    let link = roots.writable("symlink", link)?;
+    io_ok!(
+        std::os::unix::fs::symlink(target, &link),
+        "{} -> {}",
+        link.to_string_lossy(),
+        target.to_string_lossy()
+    );
+    Ok(link)
+}
+
+fn has_wildcard(part: &str) -> bool {
+    part.contains(['*', '?'])
+}
+
+// `*` and `?` within a single path component
+fn glob_regex(part: &str) -> Result<Regex, regex::Error> {
+    let mut re = String::from("^");
+    for c in part.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0u8; 4]))),
+        }
+    }
+    re.push('$');
+    Regex::new(&re)
+}
+
+// `base` is kept as written in the pattern, the filesystem is looked at
+// through `cwd.join(base)`.
+fn glob_walk(
+    cwd: &Path,
+    base: &Path,
+    parts: &[String],
+    found: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    let Some((part, rest)) = parts.split_first() else {
+        found.push(base.to_path_buf());
+        return Ok(());
+    };
+    let dir = cwd.join(base);
+
+    if !has_wildcard(part) {
+        let next = base.join(part);
+        if fs::symlink_metadata(cwd.join(&next)).is_ok() {
+            glob_walk(cwd, &next, rest, found)?;
+        }
+        return Ok(());
+    }
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    let mut names = Vec::new();
+    for entry in io_ok!(fs::read_dir(&dir), dir.to_string_lossy()) {
+        let entry = io_ok!(entry);
+        names.push((entry.file_name(), io_ok!(entry.file_type()).is_dir()));
+    }
+
+    if part == "**" {
+        // zero or more directories, symlinked ones are not descended into
+        glob_walk(cwd, base, rest, found)?;
+        for (name, is_dir) in names {
+            if is_dir && !name.to_string_lossy().starts_with('.') {
+                glob_walk(cwd, &base.join(name), parts, found)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let re = glob_regex(part).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    for (name, _) in names {
+        let name_utf8 = name.to_string_lossy();
+        // like in a shell, hidden files only match a pattern starting with `.`
+        if name_utf8.starts_with('.') && !part.starts_with('.') {
+            continue;
+        }
+        if re.is_match(&name_utf8) {
+            glob_walk(cwd, &base.join(name), rest, found)?;
+        }
+    }
+    Ok(())
+}
+
+/// Paths matching `pattern` (`*`, `?` and `**` for any number of
+/// directories), sorted. Relative patterns give relative paths.
+pub fn glob(roots: &FsRoots, pattern: &str) -> std::io::Result<Vec<PathBuf>> {
+    let mut base = PathBuf::new();
+    let mut parts = Vec::new();
+    for component in Path::new(pattern).components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => base.push(component),
+            Component::CurDir => (),
+            Component::ParentDir | Component::Normal(_) => {
+                parts.push(component.as_os_str().to_string_lossy().into_owned())
+            }
+        }
+    }
+    // the literal start of the pattern has to be readable already
+    while let Some(part) = parts.first()
+        && !has_wildcard(part)
+    {
+        base.push(parts.remove(0));
+    }
+    let abs_base = roots.absolute(&base)?;
+    if !roots
+        .read
+        .iter()
+        .any(|root| abs_base.is_lexical_subpath_of(root))
+    {
+        return Err(outside_err("glob", &abs_base));
+    }
+
+    let mut found = Vec::new();
+    glob_walk(&roots.cwd, &base, &parts, &mut found)?;
+    found.sort();
+    found.dedup();
+
+    // symlinks may still lead out of the roots
+    let mut inside = Vec::new();
+    for path in found {
+        let abs_path = roots.absolute(&path)?;
+        if abs_path.exists() && FsRoots::inside(&roots.read, &abs_path)? {
+            inside.push(path);
+        }
+    }
+    Ok(inside)
+}
+
+fn path_string(path: PathBuf) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// Adds the fs helpers to the `upkg` table.
+pub fn set_fs_fns(lua: &Lua, table: &LuaTable, env: &BuildEnv) -> LuaResult<()> {
+    let roots = FsRoots::new(env);
+
+    let fs_roots = roots.clone();
+    let install_file_fn = lua_ok!(lua.create_function(
+        move |lua, (src, dest, mode): (String, String, Option<String>)| {
+            let fs_roots = fs_roots.in_dir(require_stage(lua, "upkg.install_file")?);
+            let dest = install_file(&fs_roots, Path::new(&src), &dest, mode.as_deref())?;
+            Ok(path_string(dest))
+        }
+    ));
+    lua_ok!(table.set("install_file", install_file_fn));
+
+    let fs_roots = roots.clone();
+    let install_dir_fn = lua_ok!(lua.create_function(
+        move |lua, (dir, mode): (String, Option<String>)| {
+            let fs_roots = fs_roots.in_dir(require_stage(lua, "upkg.install_dir")?);
+            let dir = install_dir(&fs_roots, Path::new(&dir), mode.as_deref())?;
+            Ok(path_string(dir))
+        }
+    ));
+    lua_ok!(table.set("install_dir", install_dir_fn));
+
+    let fs_roots = roots.clone();
+    let write_file_fn = lua_ok!(lua.create_function(
+        move |lua, (path, contents, mode): (String, LuaString, Option<String>)| {
+            let fs_roots = fs_roots.in_dir(require_stage(lua, "upkg.write_file")?);
+            let path = write_file(
+                &fs_roots,
+                Path::new(&path),
+                &contents.as_bytes(),
+                mode.as_deref(),
+            )?;
+            Ok(path_string(path))
+        }
+    ));
+    lua_ok!(table.set("write_file", write_file_fn));
+
+    let fs_roots = roots.clone();
+    let symlink_fn = lua_ok!(
+        lua.create_function(move |lua, (target, link): (String, String)| {
+            let fs_roots = fs_roots.in_dir(require_stage(lua, "upkg.symlink")?);
+            let link = symlink(&fs_roots, Path::new(&target), Path::new(&link))?;
+            Ok(path_string(link))
+        })
+    );
+    lua_ok!(table.set("symlink", symlink_fn));
+
+    let glob_fn = lua_ok!(lua.create_function(move |lua, pattern: String| {
+        let roots = roots.in_dir(require_stage(lua, "upkg.glob")?);
+        let found = glob(&roots, &pattern)?;
+        Ok(found.into_iter().map(path_string).collect::<Vec<_>>())
+    }));
+    lua_ok!(table.set("glob", glob_fn));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::test_util::scratch_dir;
+
+    #[test]
+    fn test_fs_helpers_stay_in_the_build_dirs() {
+        let dir = scratch_dir("fs");
+        let outside = scratch_dir("fs-out");
+        fs::write(outside.join("secret"), "").unwrap();
+        fs::write(dir.join("pkgbuild.lua"), "").unwrap();
+        fs::write(dir.join("starship.desktop"), "[Desktop Entry]\n").unwrap();
+        let env = BuildEnv::new(
+            dir.join("pkgbuild.lua"),
+            Some(dir.join("build")),
+            Config::from_layer(ConfigLayer::default()),
+        )
+        .unwrap();
+        fs::create_dir_all(env.src_dir.join("target/release/deps")).unwrap();
+        fs::write(env.src_dir.join("target/release/starship"), "elf").unwrap();
+        fs::write(env.src_dir.join("target/release/deps/dep.d"), "").unwrap();
+        fs::write(env.src_dir.join("target/release/.fingerprint"), "").unwrap();
+        let roots = FsRoots::new(&env);
+        let pkg_dir = env.pkg_dir.to_string_lossy().into_owned();
+
+        let bin = install_file(
+            &roots,
+            &env.src_dir.join("target/release/starship"),
+            &format!("{}/usr/bin/", pkg_dir),
+            Some("755"),
+        )
+        .unwrap();
+        assert_eq!(bin, env.pkg_dir.join("usr/bin/starship"));
+        let meta = fs::metadata(&bin).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o755);
+
+        install_file(
+            &roots,
+            &dir.join("starship.desktop"),
+            &format!("{}/usr/share/applications/", pkg_dir),
+            None,
+        )
+        .unwrap();
+        install_dir(&roots, &env.pkg_dir.join("usr/share/doc"), None).unwrap();
+        write_file(&roots, &env.pkg_dir.join("etc/motd"), b"hi", None).unwrap();
+        symlink(
+            &roots,
+            Path::new("starship"),
+            &env.pkg_dir.join("usr/bin/ship"),
+        )
+        .unwrap();
+        assert_eq!(fs::read(env.pkg_dir.join("usr/bin/ship")).unwrap(), b"elf");
+
+        // nothing is written outside, neither directly nor through a symlink
+        let escape = outside.join("escape");
+        assert!(write_file(&roots, &escape, b"", None).is_err());
+        assert!(write_file(&roots, &env.pkg_dir.join("../../escape"), b"", None).is_err());
+        symlink(&roots, &outside, &env.pkg_dir.join("out")).unwrap();
+        let err = write_file(&roots, &env.pkg_dir.join("out/escape"), b"", None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied, "{}", err);
+        assert!(install_dir(&roots, &env.pkg_dir.join("out/sub"), None).is_err());
+        assert!(!escape.exists() && !outside.join("sub").exists());
+        assert!(!dir.join("escape").exists());
+        assert!(install_file(&roots, &outside.join("secret"), &pkg_dir, None).is_err());
+        assert!(parse_mode(Some("rwx"), 0).is_err());
+
+        let found = glob(&roots, &format!("{}/**/*", env.src_dir.to_string_lossy())).unwrap();
+        let found: Vec<_> = found
+            .iter()
+            .map(|path| path.strip_prefix(&env.src_dir).unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            found,
+            [
+                "target",
+                "target/release",
+                "target/release/deps",
+                "target/release/deps/dep.d",
+                "target/release/starship",
+            ]
+            .map(PathBuf::from)
+        );
+        // `out` points outside the build dir
+        let found = glob(&roots, &format!("{}/*", pkg_dir)).unwrap();
+        assert_eq!(found, [env.pkg_dir.join("etc"), env.pkg_dir.join("usr")]);
+        assert!(glob(&roots, &format!("{}/*", outside.to_string_lossy())).is_err());
+
+        // relative paths start at the stage working directory
+        let roots = roots.in_dir(env.pkg_dir.join("usr"));
+        write_file(&roots, Path::new("share/doc/README"), b"", None).unwrap();
+        assert!(env.pkg_dir.join("usr/share/doc/README").is_file());
+        let found = glob(&roots, "share/*").unwrap();
+        assert_eq!(
+            found,
+            ["share/applications", "share/doc"].map(PathBuf::from)
+        );
+        assert!(write_file(&roots, Path::new("../../../escape"), b"", None).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+}
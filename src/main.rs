@@ -1,59 +1,218 @@
+mod archive;
+mod cli;
+mod config;
 mod err_context;
 mod lua;
+mod patch;
+mod pkg;
 mod proto;
 mod sub_path;
 mod upkg;
+mod version;
 
+#[cfg(test)]
+mod test_util;
+
+use crate::cli::*;
+use crate::config::*;
 use crate::lua::load_lua::*;
 use crate::lua::lua_types::*;
+use crate::pkg::db::*;
 use crate::proto::*;
 use crate::sub_path::*;
+use crate::upkg::{BuildEnv, Stage};
 
+use clap::Parser;
 use mlua::prelude::*;
 
 use std::fs;
+use std::process::ExitCode;
 
-static LOCAL_INSTALL_PATH: &str = "/home/siddharth/tst/";
+fn load_package(lua: &Lua, env: &BuildEnv) -> LuaResult<Package> {
+    load_lua(lua, env)?;
+    let invalid = |err: &dyn std::fmt::Display| {
+        LuaError::external(format!(
+            "[{}:{}] invalid Package in {}: {}",
+            file!(),
+            line!(),
+            env.pkgbuild.to_string_lossy(),
+            err
+        ))
+    };
 
-fn upkg() -> LuaResult<()> {
-    let lua = lua_ok!(create_lua_instance());
+    let pkg: Package = lua
+        .from_value(lua_ok!(
+            lua.globals().get("Package"),
+            "global Package not found"
+        ))
+        .map_err(|err| invalid(&err))?;
+    pkg.validate().map_err(|err| invalid(&err))?;
+
+    Ok(pkg)
+}
+
+fn selected_stages(stages: &StageArgs, install: Option<InstallReason>) -> Vec<Stage> {
+    let mut selected = Vec::new();
+    if !stages.nodeps {
+        selected.push(Stage::Deps {
+            check: !stages.nocheck,
+        });
+    }
+    selected.push(Stage::Download);
+    selected.push(Stage::Lock);
+    if !stages.skip_verify {
+        selected.push(Stage::Verify);
+    }
+    if !stages.noextract {
+        selected.push(Stage::Extract);
+        selected.push(Stage::Patch);
+    }
+    if !stages.noprepare {
+        selected.push(Stage::Prepare);
+    }
+    if !stages.nobuild {
+        selected.push(Stage::Build);
+    }
+    if !stages.nocheck {
+        selected.push(Stage::Check);
+    }
+    selected.push(Stage::Install);
+    selected.push(Stage::Package);
+    if let Some(reason) = install {
+        selected.push(Stage::Deploy {
+            reason,
+            nodeps: stages.nodeps,
+        });
+    }
+    selected
+}
 
-    let root_path = Path::new(env!("CARGO_MANIFEST_DIR"));
-    let pkgbuild = root_path.join("test-pkg/starship/pkgbuild.lua");
+fn print_deps(label: &str, deps: &[DepInfo]) {
+    if deps.is_empty() {
+        return;
+    }
 
-    if io_ok!(pkgbuild.is_subpath_of(root_path)) {
-        lua_ok!(load_lua(&lua, &pkgbuild));
+    let names: Vec<String> = deps.iter().map(|dep| dep.to_string()).collect();
+    println!("{:<16}: {}", label, names.join("  "));
+}
 
-        let pkg: Package = lua_ok!(lua.from_value(lua_ok!(lua.globals().get("Package"))));
+fn print_info(pkg: &Package) {
+    println!("{:<16}: {}", "Name", pkg.pkg.name);
+    println!("{:<16}: {}", "Version", pkg.pkg.version());
+    println!("{:<16}: {}", "Description", pkg.pkg.desc);
+    if !pkg.url.is_empty() {
+        println!("{:<16}: {}", "URL", pkg.url);
+    }
+    if !pkg.license.is_empty() {
+        println!("{:<16}: {}", "Licenses", pkg.license.join("  "));
+    }
+    if !pkg.groups.is_empty() {
+        println!("{:<16}: {}", "Groups", pkg.groups.join("  "));
+    }
+    print_deps("Provides", &pkg.provides);
+    print_deps("Depends On", &pkg.depends);
+    print_deps("Optional Deps", &pkg.opt_depends);
+    print_deps("Make Deps", &pkg.make_depends);
+    print_deps("Check Deps", &pkg.check_depends);
+    print_deps("Conflicts With", &pkg.conflicts);
+    print_deps("Replaces", &pkg.replaces);
+    for src in &pkg.source.0 {
+        println!("{:<16}: {:?} {}", "Source", src.proto, src.location);
+    }
+}
 
-        let total_steps = 7;
-        let mut current_step = 1;
-        println!("({}/{}) Downloading Deps", current_step, total_steps);
-        lua_ok!(upkg::download_deps::download(&pkg, &pkgbuild));
+fn remove(config: &Config, names: &[String], opts: pkg::remove::RemoveOpts) -> LuaResult<()> {
+    let db = io_ok!(LocalDb::open(config.db_path()));
+    let removed = io_ok!(pkg::remove::remove_packages(
+        &db,
+        &config.install_root,
+        names,
+        opts
+    ));
+    println!("removed {} package(s)", removed.len());
+    Ok(())
+}
 
-        current_step = current_step + 1;
-        println!("({}/{}) Verifying Deps", current_step, total_steps);
-        lua_ok!(upkg::verify_deps::verify(&pkg, &pkgbuild));
+fn is_package_file(path: &std::path::Path) -> bool {
+    path.to_string_lossy()
+        .ends_with(pkg::manifest::PACKAGE_SUFFIX)
+}
 
-        current_step = current_step + 1;
-        println!("({}/{}) Extracting Deps", current_step, total_steps);
-        lua_ok!(upkg::extract_deps::extract(&pkg, &pkgbuild));
+fn upkg(cli: Cli) -> LuaResult<()> {
+    let config = io_ok!(Config::load(
+        cli.dirs.config.as_deref(),
+        ConfigLayer {
+            install_root: cli.dirs.install_root.clone(),
+            jobs: cli.dirs.jobs,
+            offline: cli.dirs.offline.then_some(true),
+            locked: cli.dirs.locked.then_some(true),
+            ..Default::default()
+        }
+    ));
 
-        Ok(())
-    } else {
-        Err(LuaError::external(format!(
-            "[{}:{}] {} is not a subpath of {}",
-            file!(),
-            line!(),
-            pkgbuild.to_string_lossy(),
-            root_path.to_string_lossy()
-        )))
+    let (pkgbuild, stages) = match &cli.command {
+        Command::Build { pkgbuild, stages } => (pkgbuild, selected_stages(stages, None)),
+        Command::Install {
+            target,
+            asdeps,
+            stages,
+        } => {
+            let reason = match asdeps {
+                true => InstallReason::dependency,
+                false => InstallReason::explicit,
+            };
+            if is_package_file(target) {
+                return upkg::install_deps::install_package_file(
+                    target,
+                    &config,
+                    reason,
+                    stages.nodeps,
+                );
+            }
+            (target, selected_stages(stages, Some(reason)))
+        }
+        Command::Remove {
+            names,
+            cascade,
+            recursive,
+        } => {
+            let opts = pkg::remove::RemoveOpts {
+                cascade: *cascade,
+                recursive: *recursive,
+            };
+            return remove(&config, names, opts);
+        }
+        Command::Cache {
+            command: CacheCommand::Clean,
+        } => {
+            let removed = io_ok!(git_cache::clean(&config.cache_dir));
+            println!("removed {} git mirror(s)", removed);
+            return Ok(());
+        }
+        Command::Fetch { pkgbuild } => (pkgbuild, vec![Stage::Download, Stage::Lock]),
+        Command::Update { pkgbuild } => (pkgbuild, vec![Stage::Update]),
+        Command::Verify { pkgbuild } => (pkgbuild, vec![Stage::Verify]),
+        Command::Info { pkgbuild } => (pkgbuild, vec![]),
+    };
+
+    let lua = lua_ok!(create_lua_instance());
+    let env = io_ok!(BuildEnv::new(pkgbuild, cli.dirs.build_dir.clone(), config));
+    let pkg = load_package(&lua, &env)?;
+
+    if let Command::Info { .. } = cli.command {
+        print_info(&pkg);
+        return Ok(());
     }
+
+    upkg::run_stages(&lua, &pkg, &env, &stages)
 }
 
-fn main() {
-    match upkg() {
-        Ok(_) => {}
-        Err(msg) => println!("{}", msg),
+fn main() -> ExitCode {
+    match upkg(Cli::parse()) {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(msg) => {
+            eprintln!("error: {}", msg);
+            ExitCode::FAILURE
+        }
     }
 }
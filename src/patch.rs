@@ -0,0 +1,622 @@
+use crate::*;
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Component, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HunkLine {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+/// One `@@ -old_start,old_len +new_start,new_len @@` block of a unified diff.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    old_start: usize,
+    old_len: usize,
+    new_start: usize,
+    new_len: usize,
+    lines: Vec<HunkLine>,
+}
+
+impl Hunk {
+    fn header(&self) -> String {
+        format!(
+            "@@ -{},{} +{},{} @@",
+            self.old_start, self.old_len, self.new_start, self.new_len
+        )
+    }
+
+    // lines before and after the hunk, swapped when reverting it
+    fn sides(&self, reverse: bool) -> (Vec<&str>, Vec<&str>, usize) {
+        let mut old = Vec::new();
+        let mut new = Vec::new();
+        for line in &self.lines {
+            match line {
+                HunkLine::Context(line) => {
+                    old.push(line.as_str());
+                    new.push(line.as_str());
+                }
+                HunkLine::Remove(line) => old.push(line.as_str()),
+                HunkLine::Add(line) => new.push(line.as_str()),
+            }
+        }
+        match reverse {
+            false => (old, new, self.old_start),
+            true => (new, old, self.new_start),
+        }
+    }
+}
+
+/// The changes to a single file, a `None` path stands for `/dev/null` (the
+/// file is created or deleted).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilePatch {
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+/// What `apply` did to the tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Applied,
+    /// The patch reverts cleanly, so it was applied before.
+    AlreadyApplied,
+}
+
+fn parse_err(line_nr: usize, msg: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("[{}:{}] line {}: {}", file!(), line!(), line_nr, msg),
+    )
+}
+
+// `a/src/main.rs\t2024-01-01 ...` -> `a/src/main.rs`
+fn header_path(rest: &str) -> Option<String> {
+    let path = rest.trim_end_matches(['\n', '\r']);
+    let path = path.split('\t').next().unwrap_or(path);
+    (path != "/dev/null").then(|| path.to_string())
+}
+
+// `-12,3` -> (12, 3), a missing length means 1
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let (start, len) = match range.split_once(',') {
+        Some((start, len)) => (start, len.parse().ok()?),
+        None => (range, 1),
+    };
+    Some((start.parse().ok()?, len))
+}
+
+fn parse_hunk_header(line: &str) -> Option<(usize, usize, usize, usize)> {
+    let ranges = line.strip_prefix("@@ -")?.split(" @@").next()?;
+    let (old, new) = ranges.split_once(" +")?;
+    let (old_start, old_len) = parse_range(old)?;
+    let (new_start, new_len) = parse_range(new)?;
+    Some((old_start, old_len, new_start, new_len))
+}
+
+/// Parses the unified diffs in `text`, anything around them (mail headers of
+/// `git format-patch`, `diff --git` and `index` lines) is skipped.
+pub fn parse(text: &str) -> std::io::Result<Vec<FilePatch>> {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let mut patches = Vec::new();
+    let mut idx = 0;
+
+    while idx < lines.len() {
+        let line = lines[idx];
+        if line.starts_with("GIT binary patch") || line.starts_with("Binary files ") {
+            return Err(parse_err(idx + 1, "binary patches are not supported"));
+        }
+        let (Some(old), Some(new)) = (
+            line.strip_prefix("--- "),
+            lines
+                .get(idx + 1)
+                .and_then(|next| next.strip_prefix("+++ ")),
+        ) else {
+            idx += 1;
+            continue;
+        };
+        let mut patch = FilePatch {
+            old_path: header_path(old),
+            new_path: header_path(new),
+            hunks: Vec::new(),
+        };
+        idx += 2;
+
+        while let Some(line) = lines.get(idx)
+            && line.starts_with("@@ ")
+        {
+            let (old_start, old_len, new_start, new_len) = parse_hunk_header(line)
+                .ok_or_else(|| parse_err(idx + 1, "malformed hunk header"))?;
+            let mut hunk = Hunk {
+                old_start,
+                old_len,
+                new_start,
+                new_len,
+                lines: Vec::new(),
+            };
+            let (mut old_left, mut new_left) = (old_len, new_len);
+            idx += 1;
+
+            while old_left > 0 || new_left > 0 {
+                let Some(line) = lines.get(idx) else {
+                    return Err(parse_err(idx, "hunk ends early"));
+                };
+                let (kind, content) = match line.chars().next() {
+                    // an empty context line some editors strip the space from
+                    Some('\n') => (' ', line.to_string()),
+                    Some(kind) => (kind, line[kind.len_utf8()..].to_string()),
+                    None => (' ', String::new()),
+                };
+                match kind {
+                    ' ' if old_left > 0 && new_left > 0 => {
+                        old_left -= 1;
+                        new_left -= 1;
+                        hunk.lines.push(HunkLine::Context(content));
+                    }
+                    '-' if old_left > 0 => {
+                        old_left -= 1;
+                        hunk.lines.push(HunkLine::Remove(content));
+                    }
+                    '+' if new_left > 0 => {
+                        new_left -= 1;
+                        hunk.lines.push(HunkLine::Add(content));
+                    }
+                    '\\' => (),
+                    _ => return Err(parse_err(idx + 1, "hunk does not match its header")),
+                }
+                idx += 1;
+                no_newline_at_end(&lines, &mut idx, &mut hunk);
+            }
+            patch.hunks.push(hunk);
+        }
+
+        if patch.hunks.is_empty() {
+            return Err(parse_err(idx, "file header without hunks"));
+        }
+        patches.push(patch);
+    }
+
+    if patches.is_empty() {
+        return Err(parse_err(lines.len(), "no unified diff found"));
+    }
+    Ok(patches)
+}
+
+// `\ No newline at end of file` refers to the line before it
+fn no_newline_at_end(lines: &[&str], idx: &mut usize, hunk: &mut Hunk) {
+    if lines.get(*idx).is_some_and(|line| line.starts_with('\\')) {
+        if let Some(HunkLine::Context(last) | HunkLine::Remove(last) | HunkLine::Add(last)) =
+            hunk.lines.last_mut()
+        {
+            last.truncate(last.trim_end_matches('\n').len());
+        }
+        *idx += 1;
+    }
+}
+
+/// `path` without its first `strip` components, like `patch -p<strip>`.
+fn strip_path(path: &str, strip: usize) -> Option<PathBuf> {
+    let mut stripped = PathBuf::new();
+    for component in Path::new(path).components().skip(strip) {
+        match component {
+            Component::Normal(part) => stripped.push(part),
+            Component::CurDir => (),
+            // a patch may not reach outside of the tree it is applied to
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!stripped.as_os_str().is_empty()).then_some(stripped)
+}
+
+// Position of `old` in `lines` at or after `from`, the one closest to
+// `expected` wins when a hunk moved.
+fn find_hunk(lines: &[String], old: &[&str], from: usize, expected: usize) -> Option<usize> {
+    let last = lines.len().checked_sub(old.len())?;
+    if from > last {
+        return None;
+    }
+    let expected = expected.clamp(from, last);
+    let matches = |pos: usize| {
+        lines[pos..pos + old.len()]
+            .iter()
+            .zip(old)
+            .all(|(a, b)| a == b)
+    };
+
+    for distance in 0..=(last - from) {
+        if let Some(pos) = expected.checked_add(distance)
+            && pos <= last
+            && matches(pos)
+        {
+            return Some(pos);
+        }
+        if let Some(pos) = expected.checked_sub(distance)
+            && pos >= from
+            && matches(pos)
+        {
+            return Some(pos);
+        }
+    }
+    None
+}
+
+// The file after applying `hunks`, or the headers of the hunks that don't fit.
+fn apply_hunks(
+    lines: &[String],
+    hunks: &[Hunk],
+    reverse: bool,
+) -> Result<Vec<String>, Vec<String>> {
+    let mut patched = Vec::new();
+    let mut rejected = Vec::new();
+    let mut pos = 0;
+    let mut offset: isize = 0;
+
+    for hunk in hunks {
+        let (old, new, start) = hunk.sides(reverse);
+        // an empty side starts after its line, the others at it
+        let start = if old.is_empty() {
+            start
+        } else {
+            start.saturating_sub(1)
+        };
+        let expected = start.saturating_add_signed(offset);
+
+        match find_hunk(lines, &old, pos, expected) {
+            Some(found) => {
+                patched.extend_from_slice(&lines[pos..found]);
+                patched.extend(new.iter().map(|line| line.to_string()));
+                pos = found + old.len();
+                offset = found as isize - start as isize;
+            }
+            None => rejected.push(hunk.header()),
+        }
+    }
+    patched.extend_from_slice(&lines[pos..]);
+
+    match rejected.is_empty() {
+        true => Ok(patched),
+        false => Err(rejected),
+    }
+}
+
+struct Rejected {
+    file: PathBuf,
+    reasons: Vec<String>,
+}
+
+type Changes = HashMap<PathBuf, Option<Vec<String>>>;
+
+fn read_lines(path: &Path) -> std::io::Result<Option<Vec<String>>> {
+    match fs::symlink_metadata(path) {
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(Error::new(
+            err.kind(),
+            format!(
+                "[{}:{}] {} {}",
+                file!(),
+                line!(),
+                path.to_string_lossy(),
+                err
+            ),
+        )),
+        Ok(meta) if !meta.is_file() => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "[{}:{}] refusing to patch, not a regular file: {}",
+                file!(),
+                line!(),
+                path.to_string_lossy()
+            ),
+        )),
+        Ok(_) => {
+            let text = io_ok!(fs::read_to_string(path), path.to_string_lossy());
+            Ok(Some(
+                text.split_inclusive('\n').map(str::to_string).collect(),
+            ))
+        }
+    }
+}
+
+// The new content of every file touched by `patches`, `None` for deleted
+// files, or everything that doesn't apply.
+fn plan(
+    patches: &[FilePatch],
+    dir: &Path,
+    strip: usize,
+    reverse: bool,
+) -> std::io::Result<Result<Changes, Vec<Rejected>>> {
+    let mut changes = Changes::new();
+    let mut rejected = Vec::new();
+
+    for patch in patches {
+        let (from, to) = match reverse {
+            false => (&patch.old_path, &patch.new_path),
+            true => (&patch.new_path, &patch.old_path),
+        };
+        let Some(name) = to.as_ref().or(from.as_ref()) else {
+            continue;
+        };
+        let Some(rel_path) = strip_path(name, strip) else {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "[{}:{}] can't strip {} components from, or it leaves the tree: {}",
+                    file!(),
+                    line!(),
+                    strip,
+                    name
+                ),
+            ));
+        };
+        let path = dir.join(&rel_path);
+
+        let current = match changes.get(&path) {
+            Some(current) => current.clone(),
+            None => read_lines(&path)?,
+        };
+        let reject = |reasons: Vec<String>| Rejected {
+            file: rel_path.clone(),
+            reasons,
+        };
+
+        let lines = match (from, current) {
+            (Some(_), Some(lines)) => lines,
+            (None, None) => Vec::new(),
+            (Some(_), None) => {
+                rejected.push(reject(vec![String::from("file does not exist")]));
+                continue;
+            }
+            (None, Some(_)) => {
+                rejected.push(reject(vec![String::from("file to create already exists")]));
+                continue;
+            }
+        };
+
+        match apply_hunks(&lines, &patch.hunks, reverse) {
+            Ok(patched) if to.is_none() && !patched.is_empty() => {
+                rejected.push(reject(vec![String::from(
+                    "file to delete has more content than the patch",
+                )]));
+            }
+            Ok(patched) => {
+                changes.insert(path, to.is_some().then_some(patched));
+            }
+            Err(hunks) => rejected.push(reject(hunks)),
+        }
+    }
+
+    match rejected.is_empty() {
+        true => Ok(Ok(changes)),
+        false => Ok(Err(rejected)),
+    }
+}
+
+// Writes the new content of `path` to a hidden file next to it, keeping the
+// mode of the file it replaces.
+fn stage_file(dir: &Path, path: &Path, content: &[String]) -> std::io::Result<PathBuf> {
+    // `dir` joined with a non-empty relative path always has a parent
+    let parent = path.parent().unwrap();
+    if !create_dir_inside(parent, dir)? {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "[{}:{}] refusing to patch {}: parent directory resolves outside of {}",
+                file!(),
+                line!(),
+                path.to_string_lossy(),
+                dir.to_string_lossy()
+            ),
+        ));
+    }
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let staged = path.with_file_name(format!(".{}.upkg-patch", name));
+    let result = fs::write(&staged, content.concat()).and_then(|_| match fs::metadata(path) {
+        Ok(meta) => fs::set_permissions(&staged, meta.permissions()),
+        Err(_) => Ok(()),
+    });
+    if let Err(err) = result {
+        let _ = fs::remove_file(&staged);
+        return Err(err).map_err(io_err_ctx!(staged.to_string_lossy()));
+    }
+    Ok(staged)
+}
+
+// Every changed file is written next to its target first and only renamed into
+// place, deletions last, once all of them are written. A failure before that
+// leaves the tree as it was.
+fn write_changes(dir: &Path, changes: Changes) -> std::io::Result<()> {
+    let mut staged = Vec::new();
+    let mut removed = Vec::new();
+    for (path, content) in changes {
+        let Some(content) = content else {
+            removed.push(path);
+            continue;
+        };
+        match stage_file(dir, &path, &content) {
+            Ok(staged_path) => staged.push((staged_path, path)),
+            Err(err) => {
+                for (staged_path, _) in &staged {
+                    let _ = fs::remove_file(staged_path);
+                }
+                return Err(err);
+            }
+        }
+    }
+
+    for (staged_path, path) in staged {
+        io_ok!(
+            fs::rename(&staged_path, &path),
+            "{} -> {}",
+            staged_path.to_string_lossy(),
+            path.to_string_lossy()
+        );
+    }
+    for path in removed {
+        io_ok!(fs::remove_file(&path), path.to_string_lossy());
+    }
+    Ok(())
+}
+
+/// Applies `patches` to the tree at `dir`, all files or none. A patch that
+/// reverts cleanly is reported as already applied, one that does neither
+/// fails with every rejected hunk.
+pub fn apply(patches: &[FilePatch], dir: &Path, strip: usize) -> std::io::Result<Outcome> {
+    let rejected = match plan(patches, dir, strip, false)? {
+        Ok(changes) => {
+            write_changes(dir, changes)?;
+            return Ok(Outcome::Applied);
+        }
+        Err(rejected) => rejected,
+    };
+    if plan(patches, dir, strip, true)?.is_ok() {
+        return Ok(Outcome::AlreadyApplied);
+    }
+
+    let report: Vec<String> = rejected
+        .iter()
+        .map(|rejected| {
+            format!(
+                "  {}: {}",
+                rejected.file.to_string_lossy(),
+                rejected.reasons.join(", ")
+            )
+        })
+        .collect();
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        format!(
+            "[{}:{}] patch does not apply to {}, rejected:\n{}",
+            file!(),
+            line!(),
+            dir.to_string_lossy(),
+            report.join("\n")
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+
+    const PATCH: &str = "From 1234 Mon Sep 17 00:00:00 2001
+Subject: [PATCH] fix greeting
+
+diff --git a/src/main.c b/src/main.c
+index 1111111..2222222 100644
+--- a/src/main.c
++++ b/src/main.c
+@@ -1,4 +1,4 @@
+ int main() {
+-    puts(\"helo\");
++    puts(\"hello\");
+     return 0;
+ }
+@@ -8,2 +8,3 @@ int unused() {
+ int later() {
++    return 1;
+ }
+\\ No newline at end of file
+--- /dev/null
++++ b/NEWS
+@@ -0,0 +1 @@
++fixed the greeting
+--
+2.45.0
+";
+
+    const MAIN_C: &str = "int main() {
+    puts(\"helo\");
+    return 0;
+}
+
+int unused() {
+}
+int later() {
+}";
+
+    #[test]
+    fn test_parse_unified_diff() {
+        let patches = parse(PATCH).unwrap();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].old_path.as_deref(), Some("a/src/main.c"));
+        assert_eq!(patches[0].hunks.len(), 2);
+        assert_eq!(
+            patches[0].hunks[1].lines.last(),
+            Some(&HunkLine::Context(String::from("}")))
+        );
+        assert_eq!(patches[1].old_path, None);
+        assert_eq!(patches[1].hunks[0].header(), "@@ -0,0 +1,1 @@");
+
+        assert!(parse("just some text\n").is_err());
+        assert!(parse("--- a/x\n+++ b/x\n@@ -1,2 +1,2 @@\n-a\n").is_err());
+        assert_eq!(
+            strip_path("a/src/main.c", 1),
+            Some(PathBuf::from("src/main.c"))
+        );
+        assert_eq!(strip_path("a/../../etc/passwd", 1), None);
+        assert_eq!(strip_path("main.c", 1), None);
+    }
+
+    #[test]
+    fn test_apply_is_idempotent_and_reports_rejects() {
+        let dir = scratch_dir("patch-apply");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        // two lines up front move the second hunk
+        fs::write(dir.join("src/main.c"), format!("// a\n// b\n{}", MAIN_C)).unwrap();
+        let patches = parse(PATCH).unwrap();
+
+        assert_eq!(apply(&patches, &dir, 1).unwrap(), Outcome::Applied);
+        let main_c = fs::read_to_string(dir.join("src/main.c")).unwrap();
+        assert!(main_c.contains("puts(\"hello\")"));
+        assert!(
+            main_c.ends_with("int later() {\n    return 1;\n}"),
+            "{}",
+            main_c
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("NEWS")).unwrap(),
+            "fixed the greeting\n"
+        );
+
+        assert_eq!(apply(&patches, &dir, 1).unwrap(), Outcome::AlreadyApplied);
+
+        fs::write(dir.join("src/main.c"), MAIN_C.replace("helo", "bye")).unwrap();
+        fs::remove_file(dir.join("NEWS")).unwrap();
+        let err = apply(&patches, &dir, 1).unwrap_err().to_string();
+        assert!(err.contains("src/main.c: @@ -1,4 +1,4 @@"), "{}", err);
+        assert!(!err.contains("+8,3"), "{}", err);
+        // nothing is written when a hunk is rejected
+        assert!(!dir.join("NEWS").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_write_leaves_the_tree_alone() {
+        let dir = scratch_dir("patch-atomic");
+        let outside = scratch_dir("patch-atomic-out");
+        fs::write(dir.join("a.txt"), "old\n").unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("out")).unwrap();
+        let patches = parse(
+            "--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-old\n+new\n\
+             --- /dev/null\n+++ b/out/newdir/new.txt\n@@ -0,0 +1 @@\n+new\n",
+        )
+        .unwrap();
+
+        let err = apply(&patches, &dir, 1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied, "{}", err);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "old\n");
+        assert!(!dir.join(".a.txt.upkg-patch").exists());
+        // not even the missing directories were created through the symlink
+        assert!(fs::read_dir(&outside).unwrap().next().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+}
@@ -0,0 +1,403 @@
+use crate::pkg::manifest::*;
+use crate::*;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+static LOCK_NAME: &str = "db.lck";
+static LOCAL_DIR_NAME: &str = "local";
+static DESC_NAME: &str = "desc.toml";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum InstallReason {
+    explicit,
+    dependency,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InstalledPackage {
+    pub reason: InstallReason,
+    pub install_date: u64,
+    pub package: Manifest,
+}
+
+impl InstalledPackage {
+    /// `<name>-<ver>-<rel>`, the directory name of the entry in the database.
+    pub fn entry_name(&self) -> String {
+        format!(
+            "{}-{}-{}",
+            self.package.name, self.package.ver, self.package.rel
+        )
+    }
+}
+
+/// Database of installed packages, one `local/<name>-<ver>-<rel>/desc.toml` per
+/// package. The database stays locked for as long as the handle is alive,
+/// except for a read-only handle on a database that has no lock file yet.
+pub struct LocalDb {
+    local_dir: PathBuf,
+    _lock: Option<fs::File>,
+}
+
+fn db_err(kind: std::io::ErrorKind, msg: String) -> std::io::Error {
+    std::io::Error::new(kind, msg)
+}
+
+impl LocalDb {
+    fn open_with<P: AsRef<std::path::Path>>(
+        db_path: P,
+        exclusive: bool,
+    ) -> std::io::Result<LocalDb> {
+        let db_path = db_path.as_ref();
+        let local_dir = db_path.join(LOCAL_DIR_NAME);
+        let lock_path = db_path.join(LOCK_NAME);
+
+        let lock = if exclusive {
+            io_ok!(fs::create_dir_all(&local_dir), local_dir.to_string_lossy());
+            io_ok!(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(&lock_path),
+                lock_path.to_string_lossy()
+            )
+        } else {
+            // readers create nothing, a database nobody wrote to yet is empty
+            // and one without a readable lock file is read unlocked
+            match fs::File::open(&lock_path) {
+                Ok(lock) => lock,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied
+                    ) =>
+                {
+                    return Ok(LocalDb {
+                        local_dir,
+                        _lock: None,
+                    });
+                }
+                Err(err) => return Err(err).map_err(io_err_ctx!(lock_path.to_string_lossy())),
+            }
+        };
+
+        let acquired = if exclusive {
+            lock.try_lock()
+        } else {
+            lock.try_lock_shared()
+        };
+        match acquired {
+            Ok(()) => (),
+            Err(fs::TryLockError::WouldBlock) => {
+                println!("waiting for another upkg holding {:?}...", lock_path);
+                if exclusive {
+                    io_ok!(lock.lock(), lock_path.to_string_lossy());
+                } else {
+                    io_ok!(lock.lock_shared(), lock_path.to_string_lossy());
+                }
+            }
+            Err(fs::TryLockError::Error(err)) => {
+                return Err(err).map_err(io_err_ctx!(lock_path.to_string_lossy()));
+            }
+        }
+
+        Ok(LocalDb {
+            local_dir,
+            _lock: Some(lock),
+        })
+    }
+
+    /// Opens the database for modification, waiting for other upkg processes
+    /// to release it.
+    pub fn open<P: AsRef<std::path::Path>>(db_path: P) -> std::io::Result<LocalDb> {
+        LocalDb::open_with(db_path, true)
+    }
+
+    /// Opens the database for queries, concurrent readers don't block each other.
+    pub fn open_read_only<P: AsRef<std::path::Path>>(db_path: P) -> std::io::Result<LocalDb> {
+        LocalDb::open_with(db_path, false)
+    }
+
+    fn read_entry(&self, entry_dir: &std::path::Path) -> std::io::Result<InstalledPackage> {
+        let desc_path = entry_dir.join(DESC_NAME);
+        let data = io_ok!(fs::read_to_string(&desc_path), desc_path.to_string_lossy());
+
+        toml::from_str(&data).map_err(|err| {
+            db_err(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "[{}:{}] corrupt database entry {}: {}",
+                    file!(),
+                    line!(),
+                    desc_path.to_string_lossy(),
+                    err
+                ),
+            )
+        })
+    }
+
+    pub fn packages(&self) -> std::io::Result<Vec<InstalledPackage>> {
+        let mut packages = Vec::new();
+
+        let entries = match fs::read_dir(&self.local_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(packages),
+            Err(err) => return Err(err).map_err(io_err_ctx!(self.local_dir.to_string_lossy())),
+        };
+        for entry in entries {
+            let entry = io_ok!(entry, self.local_dir.to_string_lossy());
+            // leftovers of an interrupted write start with a dot and are ignored
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            packages.push(self.read_entry(&entry.path())?);
+        }
+
+        packages.sort_by(|lhs, rhs| lhs.package.name.cmp(&rhs.package.name));
+        Ok(packages)
+    }
+
+    pub fn find(&self, name: &str) -> std::io::Result<Option<InstalledPackage>> {
+        Ok(self
+            .packages()?
+            .into_iter()
+            .filter(|installed| installed.package.name == name)
+            .max_by_key(|installed| installed.install_date))
+    }
+
+    // Renames the entry `entry_name` to a hidden name `packages()` skips, so it
+    // vanishes in one step. The returned directory is deleted by the caller.
+    fn move_aside(&self, entry_name: &str) -> std::io::Result<PathBuf> {
+        let entry_dir = self.local_dir.join(entry_name);
+        let trash_dir = self.local_dir.join(format!(".{}.del", entry_name));
+        if trash_dir.exists() {
+            io_ok!(fs::remove_dir_all(&trash_dir), trash_dir.to_string_lossy());
+        }
+        io_ok!(
+            fs::rename(&entry_dir, &trash_dir),
+            "{} -> {}",
+            entry_dir.to_string_lossy(),
+            trash_dir.to_string_lossy()
+        );
+        Ok(trash_dir)
+    }
+
+    /// Records `installed`, replacing any entry of a package with the same name.
+    /// The entry is written into a hidden directory first, an older entry is
+    /// renamed aside before the new one is renamed into place and deleted last,
+    /// so a crash never leaves a half written entry behind.
+    pub fn add(&self, installed: &InstalledPackage) -> std::io::Result<()> {
+        let entry_name = installed.entry_name();
+        let tmp_dir = self.local_dir.join(format!(".{}.tmp", entry_name));
+        let entry_dir = self.local_dir.join(&entry_name);
+
+        if tmp_dir.exists() {
+            io_ok!(fs::remove_dir_all(&tmp_dir), tmp_dir.to_string_lossy());
+        }
+        io_ok!(fs::create_dir_all(&tmp_dir), tmp_dir.to_string_lossy());
+
+        let desc = toml::to_string(installed).map_err(|err| {
+            db_err(
+                std::io::ErrorKind::InvalidData,
+                format!("[{}:{}] {}: {}", file!(), line!(), entry_name, err),
+            )
+        })?;
+        let desc_path = tmp_dir.join(DESC_NAME);
+        let mut desc_file = io_ok!(fs::File::create(&desc_path), desc_path.to_string_lossy());
+        io_ok!(
+            desc_file.write_all(desc.as_bytes()),
+            desc_path.to_string_lossy()
+        );
+        io_ok!(desc_file.sync_all(), desc_path.to_string_lossy());
+
+        let previous = self.find(&installed.package.name)?;
+        let replaced = if entry_dir.exists() {
+            Some(self.move_aside(&entry_name)?)
+        } else {
+            None
+        };
+        io_ok!(
+            fs::rename(&tmp_dir, &entry_dir),
+            "{} -> {}",
+            tmp_dir.to_string_lossy(),
+            entry_dir.to_string_lossy()
+        );
+        if let Some(trash_dir) = replaced {
+            io_ok!(fs::remove_dir_all(&trash_dir), trash_dir.to_string_lossy());
+        }
+
+        if let Some(previous) = previous
+            && previous.entry_name() != entry_name
+        {
+            let trash_dir = self.move_aside(&previous.entry_name())?;
+            io_ok!(fs::remove_dir_all(&trash_dir), trash_dir.to_string_lossy());
+        }
+
+        io_ok!(fs::File::open(&self.local_dir).and_then(|dir| dir.sync_all()));
+        Ok(())
+    }
+
+    /// Drops the entry of `name`, the installed files are left alone.
+    pub fn remove(&self, name: &str) -> std::io::Result<()> {
+        let installed = self.find(name)?.ok_or_else(|| {
+            db_err(
+                std::io::ErrorKind::NotFound,
+                format!("[{}:{}] package not installed: {}", file!(), line!(), name),
+            )
+        })?;
+
+        let trash_dir = self.move_aside(&installed.entry_name())?;
+        io_ok!(fs::remove_dir_all(&trash_dir), trash_dir.to_string_lossy());
+
+        Ok(())
+    }
+
+    /// Maps every non directory path (relative to the install root) to the name
+    /// of the installed package owning it.
+    pub fn file_owners(&self) -> std::io::Result<HashMap<String, String>> {
+        let mut owners = HashMap::new();
+
+        for installed in self.packages()? {
+            for file in installed.package.files {
+                if file.kind != FileKind::dir {
+                    owners.insert(file.path, installed.package.name.clone());
+                }
+            }
+        }
+
+        Ok(owners)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+
+    fn installed(name: &str, ver: &str, files: &[&str]) -> InstalledPackage {
+        InstalledPackage {
+            reason: InstallReason::explicit,
+            install_date: unix_time_now(),
+            package: Manifest {
+                name: name.to_string(),
+                ver: ver.to_string(),
+                rel: 1,
+                desc: String::new(),
+                arch: String::from("any"),
+                url: String::new(),
+                license: vec![],
+                groups: vec![],
+                depends: vec![],
+                opt_depends: vec![],
+                provides: vec![],
+                conflicts: vec![],
+                replaces: vec![],
+                packager: String::from("tester"),
+                build_date: 0,
+                installed_size: 0,
+                files: files
+                    .iter()
+                    .map(|path| FileEntry {
+                        path: path.to_string(),
+                        kind: FileKind::file,
+                        mode: 0o644,
+                        size: 0,
+                        sha256: None,
+                        target: None,
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_add_replace_remove() {
+        let dir = scratch_dir("db-crud");
+        let db = LocalDb::open(&dir).unwrap();
+
+        db.add(&installed("hello", "1.0", &["usr/bin/hello"]))
+            .unwrap();
+        db.add(&installed("world", "2.0", &["usr/bin/world"]))
+            .unwrap();
+        db.add(&installed("hello", "1.1", &["usr/bin/hello"]))
+            .unwrap();
+
+        let names: Vec<String> = db
+            .packages()
+            .unwrap()
+            .iter()
+            .map(|p| p.entry_name())
+            .collect();
+        assert_eq!(names, ["hello-1.1-1", "world-2.0-1"]);
+        assert_eq!(
+            db.file_owners()
+                .unwrap()
+                .get("usr/bin/world")
+                .map(String::as_str),
+            Some("world")
+        );
+
+        // reinstalling the same version swaps the entry and leaves nothing behind
+        db.add(&installed("world", "2.0", &["usr/bin/world2"]))
+            .unwrap();
+        assert!(db.file_owners().unwrap().contains_key("usr/bin/world2"));
+        let entries: Vec<String> = fs::read_dir(dir.join("local"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(entries.len(), 2, "{:?}", entries);
+
+        db.remove("hello").unwrap();
+        assert!(db.find("hello").unwrap().is_none());
+        assert!(db.remove("hello").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_write_is_ignored() {
+        let dir = scratch_dir("db-crash");
+        let db = LocalDb::open(&dir).unwrap();
+        fs::create_dir_all(dir.join("local/.broken-1.0-1.tmp")).unwrap();
+
+        assert!(db.packages().unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_open_creates_nothing() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("db-read-only");
+        let db = LocalDb::open_read_only(dir.join("missing")).unwrap();
+        assert!(db.packages().unwrap().is_empty());
+        assert!(!dir.join("missing").exists());
+
+        // an existing database is read from a directory the user can't write to
+        LocalDb::open(dir.join("db"))
+            .unwrap()
+            .add(&installed("hello", "1.0", &["usr/bin/hello"]))
+            .unwrap();
+        fs::set_permissions(dir.join("db"), fs::Permissions::from_mode(0o555)).unwrap();
+        let db = LocalDb::open_read_only(dir.join("db")).unwrap();
+        assert!(db.find("hello").unwrap().is_some());
+        fs::set_permissions(dir.join("db"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        // and so is a fresh one in such a directory
+        let empty = dir.join("empty");
+        fs::create_dir_all(&empty).unwrap();
+        fs::set_permissions(&empty, fs::Permissions::from_mode(0o555)).unwrap();
+        let db = LocalDb::open_read_only(&empty).unwrap();
+        assert!(db.packages().unwrap().is_empty());
+        assert!(fs::read_dir(&empty).unwrap().next().is_none());
+        fs::set_permissions(&empty, fs::Permissions::from_mode(0o755)).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+use crate::pkg::db::*;
+use crate::pkg::manifest::*;
+use crate::pkg::package_file::*;
+use crate::pkg::remove::remove_owned_files;
+use crate::*;
+
+use std::collections::HashSet;
+
+fn conflict_err(conflicts: &[String]) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        format!(
+            "[{}:{}] conflicting files:\n  {}",
+            file!(),
+            line!(),
+            conflicts.join("\n  ")
+        ),
+    )
+}
+
+/// Files of `manifest` that already exist below `root` and are not owned by a
+/// previous version of the same package.
+fn find_conflicts(
+    db: &LocalDb,
+    manifest: &Manifest,
+    previous: Option<&InstalledPackage>,
+    root: &std::path::Path,
+) -> std::io::Result<Vec<String>> {
+    let owned: HashSet<&str> = previous
+        .map(|previous| {
+            previous
+                .package
+                .files
+                .iter()
+                .map(|file| file.path.as_str())
+                .collect()
+        })
+        .unwrap_or_default();
+    let owners = db.file_owners()?;
+    let mut conflicts = Vec::new();
+
+    for file in &manifest.files {
+        if file.kind == FileKind::dir || owned.contains(file.path.as_str()) {
+            continue;
+        }
+        if fs::symlink_metadata(root.join(&file.path)).is_err() {
+            continue;
+        }
+
+        match owners.get(&file.path) {
+            Some(owner) => conflicts.push(format!("{} is owned by {}", file.path, owner)),
+            None => conflicts.push(format!("{} exists in filesystem", file.path)),
+        }
+    }
+
+    Ok(conflicts)
+}
+
+/// Installs the package archive `pkg_file` into `root` and records it in `db`.
+/// Files left over from a previously installed version are removed.
+pub fn install_package<P, R>(
+    db: &LocalDb,
+    pkg_file: P,
+    root: R,
+    reason: InstallReason,
+) -> std::io::Result<InstalledPackage>
+where
+    P: AsRef<std::path::Path>,
+    R: AsRef<std::path::Path>,
+{
+    let root = root.as_ref();
+    let manifest = read_manifest(pkg_file.as_ref())?;
+    let previous = db.find(&manifest.name)?;
+
+    let conflicts = find_conflicts(db, &manifest, previous.as_ref(), root)?;
+    if !conflicts.is_empty() {
+        return Err(conflict_err(&conflicts));
+    }
+
+    match &previous {
+        Some(previous) => {
+            let action = match manifest.version().cmp(&previous.package.version()) {
+                std::cmp::Ordering::Less => "downgrading",
+                std::cmp::Ordering::Equal => "reinstalling",
+                std::cmp::Ordering::Greater => "upgrading",
+            };
+            println!(
+                "{} {} ({} -> {}) in {:?}",
+                action,
+                manifest.name,
+                previous.package.version(),
+                manifest.version(),
+                root
+            );
+        }
+        None => println!(
+            "installing {}-{} into {:?}",
+            manifest.name,
+            manifest.version(),
+            root
+        ),
+    }
+    unpack_package(pkg_file.as_ref(), root)?;
+
+    if let Some(previous) = &previous {
+        let kept: HashSet<&str> = manifest
+            .files
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect();
+        remove_owned_files(root, &previous.package.files, &kept)?;
+    }
+
+    let installed = InstalledPackage {
+        // upgrading a dependency doesn't make it explicitly installed and vice versa
+        reason: previous
+            .as_ref()
+            .map(|previous| previous.reason)
+            .filter(|_| reason == InstallReason::dependency)
+            .unwrap_or(reason),
+        install_date: unix_time_now(),
+        package: manifest,
+    };
+    db.add(&installed)?;
+
+    Ok(installed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lua::lua_types::*;
+    use crate::test_util::scratch_dir;
+    use std::path::PathBuf;
+
+    // Stages `files` and packs them as `name-ver`, returning the package path.
+    fn build_package(dir: &std::path::Path, name: &str, ver: &str, files: &[&str]) -> PathBuf {
+        let pkg_dir = dir.join(format!("stage-{}-{}", name, ver));
+        for file in files {
+            let path = pkg_dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, ver).unwrap();
+        }
+
+        let lua = Lua::new();
+        let pkg: Package = lua
+            .from_value(
+                lua.load(format!(
+                    "return {{ pkg = {{ name = '{}', ver = '{}', desc = '' }}, depends = {{}}, source = {{}}, checksum = {{}} }}",
+                    name, ver
+                ))
+                .eval()
+                .unwrap(),
+            )
+            .unwrap();
+        let manifest = Manifest::from_staging(&pkg, &pkg_dir, "tester").unwrap();
+        create_package(&manifest, &pkg_dir, dir).unwrap()
+    }
+
+    #[test]
+    fn test_install_upgrade_and_conflicts() {
+        let dir = scratch_dir("install-upgrade");
+        let root = dir.join("root");
+        let db = LocalDb::open(dir.join("db")).unwrap();
+
+        let v1 = build_package(&dir, "hello", "1.0", &["usr/bin/hello", "usr/share/old"]);
+        let v2 = build_package(&dir, "hello", "2.0", &["usr/bin/hello", "usr/share/new"]);
+        install_package(&db, &v1, &root, InstallReason::explicit).unwrap();
+        install_package(&db, &v2, &root, InstallReason::dependency).unwrap();
+
+        assert_eq!(fs::read(root.join("usr/bin/hello")).unwrap(), b"2.0");
+        assert!(!root.join("usr/share/old").exists());
+        let installed = db.find("hello").unwrap().unwrap();
+        assert_eq!(installed.package.ver, "2.0");
+        assert_eq!(installed.reason, InstallReason::explicit);
+
+        let other = build_package(&dir, "other", "1.0", &["usr/bin/hello"]);
+        let err = install_package(&db, &other, &root, InstallReason::explicit).unwrap_err();
+        assert!(err.to_string().contains("usr/bin/hello is owned by hello"));
+
+        fs::write(root.join("usr/bin/stray"), b"").unwrap();
+        let stray = build_package(&dir, "stray", "1.0", &["usr/bin/stray"]);
+        let err = install_package(&db, &stray, &root, InstallReason::explicit).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("usr/bin/stray exists in filesystem")
+        );
+        assert!(db.find("stray").unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_install_keeps_existing_and_symlinked_dirs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("install-symlinked");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::set_permissions(root.join("usr"), fs::Permissions::from_mode(0o750)).unwrap();
+        std::os::unix::fs::symlink("usr/bin", root.join("bin")).unwrap();
+        let db = LocalDb::open(dir.join("db")).unwrap();
+
+        let v1 = build_package(
+            &dir,
+            "hello",
+            "1.0",
+            &["bin/hello", "bin/old", "usr/share/doc"],
+        );
+        let v2 = build_package(&dir, "hello", "2.0", &["bin/hello", "usr/share/doc"]);
+        install_package(&db, &v1, &root, InstallReason::explicit).unwrap();
+        install_package(&db, &v2, &root, InstallReason::explicit).unwrap();
+
+        assert!(fs::symlink_metadata(root.join("bin")).unwrap().is_symlink());
+        assert_eq!(fs::read(root.join("usr/bin/hello")).unwrap(), b"2.0");
+        assert!(!root.join("usr/bin/old").exists());
+        let mode = fs::metadata(root.join("usr")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+
+        let installed = db.find("hello").unwrap().unwrap();
+        remove_owned_files(&root, &installed.package.files, &HashSet::new()).unwrap();
+        assert!(fs::symlink_metadata(root.join("bin")).unwrap().is_symlink());
+        assert!(!root.join("usr/bin/hello").exists() && root.join("usr/bin").is_dir());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+use crate::lua::lua_types::*;
+use crate::upkg::verify_deps::calc_checksum;
+use crate::version::*;
+use crate::*;
+
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use std::os::unix::fs::PermissionsExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub static MANIFEST_NAME: &str = ".MANIFEST.toml";
+pub static PACKAGE_SUFFIX: &str = ".upkg.tar.zst";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum FileKind {
+    file,
+    dir,
+    symlink,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Path relative to the install root, without a leading `/`.
+    pub path: String,
+    pub kind: FileKind,
+    pub mode: u32,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+/// Metadata shipped as `.MANIFEST.toml` at the top of every package archive.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub name: String,
+    pub ver: String,
+    pub rel: u32,
+    pub desc: String,
+    pub arch: String,
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub license: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+
+    #[serde(default)]
+    pub depends: Vec<DepInfo>,
+    #[serde(default)]
+    pub opt_depends: Vec<DepInfo>,
+    #[serde(default)]
+    pub provides: Vec<DepInfo>,
+    #[serde(default)]
+    pub conflicts: Vec<DepInfo>,
+    #[serde(default)]
+    pub replaces: Vec<DepInfo>,
+
+    pub packager: String,
+    pub build_date: u64,
+    pub installed_size: u64,
+
+    #[serde(default)]
+    pub files: Vec<FileEntry>,
+}
+
+pub fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+fn collect_entries(
+    root: &std::path::Path,
+    dir: &std::path::Path,
+    entries: &mut Vec<FileEntry>,
+) -> std::io::Result<()> {
+    let mut children: Vec<_> = io_ok!(fs::read_dir(dir), dir.to_string_lossy())
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(io_err_ctx!(dir.to_string_lossy()))?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let path = child.path();
+        let meta = io_ok!(fs::symlink_metadata(&path), path.to_string_lossy());
+        let rel_path = path
+            .strip_prefix(root)
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        let mode = meta.permissions().mode() & 0o7777;
+
+        if meta.file_type().is_symlink() {
+            let target = io_ok!(fs::read_link(&path), path.to_string_lossy());
+            entries.push(FileEntry {
+                path: rel_path,
+                kind: FileKind::symlink,
+                mode,
+                size: 0,
+                sha256: None,
+                target: Some(target.to_string_lossy().into_owned()),
+            });
+        } else if meta.is_dir() {
+            entries.push(FileEntry {
+                path: rel_path,
+                kind: FileKind::dir,
+                mode,
+                size: 0,
+                sha256: None,
+                target: None,
+            });
+            collect_entries(root, &path, entries)?;
+        } else {
+            let sha256 = io_ok!(calc_checksum::<_, Sha256>(&path), path.to_string_lossy());
+            entries.push(FileEntry {
+                path: rel_path,
+                kind: FileKind::file,
+                mode,
+                size: meta.len(),
+                sha256: Some(format!("{:x}", sha256)),
+                target: None,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+impl Manifest {
+    /// Describes `pkg` with the file tree `Install()` staged into `pkg_dir`.
+    pub fn from_staging<P: AsRef<std::path::Path>>(
+        pkg: &Package,
+        pkg_dir: P,
+        packager: &str,
+    ) -> std::io::Result<Manifest> {
+        let mut files = Vec::new();
+        collect_entries(pkg_dir.as_ref(), pkg_dir.as_ref(), &mut files)?;
+
+        Ok(Manifest {
+            name: pkg.pkg.name.clone(),
+            ver: pkg.pkg.ver.clone(),
+            rel: pkg.pkg.rel(),
+            desc: pkg.pkg.desc.clone(),
+            arch: pkg.pkg.arch().to_string(),
+            url: pkg.url.clone(),
+            license: pkg.license.clone(),
+            groups: pkg.groups.clone(),
+            depends: pkg.depends.clone(),
+            opt_depends: pkg.opt_depends.clone(),
+            provides: pkg.provides.clone(),
+            conflicts: pkg.conflicts.clone(),
+            replaces: pkg.replaces.clone(),
+            packager: packager.to_string(),
+            build_date: unix_time_now(),
+            installed_size: files.iter().map(|file| file.size).sum(),
+            files,
+        })
+    }
+
+    pub fn to_toml(&self) -> std::io::Result<String> {
+        toml::to_string(self).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("[{}:{}] {}: {}", file!(), line!(), self.name, err),
+            )
+        })
+    }
+
+    pub fn from_toml(data: &str) -> std::io::Result<Manifest> {
+        toml::from_str(data).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("[{}:{}] invalid manifest: {}", file!(), line!(), err),
+            )
+        })
+    }
+
+    pub fn version(&self) -> Version {
+        Version::new(&self.ver, self.rel)
+    }
+
+    /// Whether this package, by name or through one of its `provides`, fulfills
+    /// `dep`.
+    pub fn satisfies(&self, dep: &Dependency) -> bool {
+        dep.satisfied_by(&self.name, Some(&self.version()))
+            || self
+                .provides
+                .iter()
+                .filter_map(|provided| provided.dependency().ok())
+                .any(|provided| dep.satisfied_by(&provided.name, provided.provided_version()))
+    }
+
+    pub fn package_file_name(&self) -> String {
+        package_file_name(&self.name, &self.ver, self.rel, &self.arch)
+    }
+}
+
+/// `<name>-<ver>-<rel>-<arch>.upkg.tar.zst`
+pub fn package_file_name(name: &str, ver: &str, rel: u32, arch: &str) -> String {
+    format!("{}-{}-{}-{}{}", name, ver, rel, arch, PACKAGE_SUFFIX)
+}
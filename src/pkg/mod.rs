@@ -0,0 +1,6 @@
+pub mod db;
+pub mod install;
+pub mod manifest;
+pub mod package_file;
+pub mod remove;
+pub mod resolve;
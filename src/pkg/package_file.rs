@@ -0,0 +1,360 @@
+use crate::archive::extract::sanitize_entry_path;
+use crate::archive::format::*;
+use crate::pkg::manifest::*;
+use crate::*;
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+fn entry_header(kind: tar::EntryType, mode: u32, size: u64, mtime: u64) -> tar::Header {
+    // fakeroot style: everything in the package is owned by root
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_mode(mode);
+    header.set_size(size);
+    header.set_mtime(mtime);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
+
+fn append_tree<W: Write>(
+    builder: &mut tar::Builder<W>,
+    manifest: &Manifest,
+    pkg_dir: &std::path::Path,
+) -> std::io::Result<()> {
+    let manifest_toml = manifest.to_toml()?;
+    let mut header = entry_header(
+        tar::EntryType::Regular,
+        0o644,
+        manifest_toml.len() as u64,
+        manifest.build_date,
+    );
+    io_ok!(
+        builder.append_data(&mut header, MANIFEST_NAME, manifest_toml.as_bytes()),
+        MANIFEST_NAME
+    );
+
+    for entry in &manifest.files {
+        let src_path = pkg_dir.join(&entry.path);
+        match entry.kind {
+            FileKind::dir => {
+                let mut header = entry_header(
+                    tar::EntryType::Directory,
+                    entry.mode,
+                    0,
+                    manifest.build_date,
+                );
+                io_ok!(
+                    builder.append_data(&mut header, &entry.path, std::io::empty()),
+                    entry.path
+                );
+            }
+            FileKind::symlink => {
+                let mut header =
+                    entry_header(tar::EntryType::Symlink, entry.mode, 0, manifest.build_date);
+                let target = entry.target.as_deref().unwrap_or_default();
+                io_ok!(
+                    builder.append_link(&mut header, &entry.path, target),
+                    entry.path
+                );
+            }
+            FileKind::file => {
+                let mut header = entry_header(
+                    tar::EntryType::Regular,
+                    entry.mode,
+                    entry.size,
+                    manifest.build_date,
+                );
+                let file = io_ok!(fs::File::open(&src_path), src_path.to_string_lossy());
+                io_ok!(
+                    builder.append_data(&mut header, &entry.path, file),
+                    entry.path
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Packs the staged tree of `manifest` from `pkg_dir` into a zstd compressed
+/// tarball inside `dest_dir`, returning its path. The manifest is always the
+/// first member so it can be read without unpacking the whole package.
+pub fn create_package<P, D>(
+    manifest: &Manifest,
+    pkg_dir: P,
+    dest_dir: D,
+) -> std::io::Result<PathBuf>
+where
+    P: AsRef<std::path::Path>,
+    D: AsRef<std::path::Path>,
+{
+    let file_name = manifest.package_file_name();
+    let dest_path = dest_dir.as_ref().join(&file_name);
+    let part_path = dest_dir.as_ref().join(format!(".{}.part", file_name));
+    println!("creating package: {:?}", dest_path);
+
+    let result = (|| -> std::io::Result<()> {
+        let file = io_ok!(fs::File::create(&part_path), part_path.to_string_lossy());
+        let encoder = io_ok!(zstd::stream::write::Encoder::new(file, 0));
+        let mut builder = tar::Builder::new(encoder);
+
+        append_tree(&mut builder, manifest, pkg_dir.as_ref())?;
+
+        let encoder = io_ok!(builder.into_inner());
+        let file = io_ok!(encoder.finish());
+        io_ok!(file.sync_all(), part_path.to_string_lossy());
+        Ok(())
+    })();
+
+    if let Err(err) = result {
+        let _ = fs::remove_file(&part_path);
+        return Err(err);
+    }
+
+    io_ok!(
+        fs::rename(&part_path, &dest_path),
+        "{} -> {}",
+        part_path.to_string_lossy(),
+        dest_path.to_string_lossy()
+    );
+
+    Ok(dest_path)
+}
+
+fn open_package<P: AsRef<std::path::Path>>(
+    pkg_file: P,
+) -> std::io::Result<tar::Archive<Box<dyn Read>>> {
+    let pkg_file_utf8 = pkg_file.as_ref().to_string_lossy();
+    let file = io_ok!(fs::File::open(pkg_file.as_ref()), pkg_file_utf8);
+
+    Ok(tar::Archive::new(io_ok!(
+        decoder(Compression::Zstd, file),
+        pkg_file_utf8
+    )))
+}
+
+pub fn read_manifest<P: AsRef<std::path::Path>>(pkg_file: P) -> std::io::Result<Manifest> {
+    let pkg_file_utf8 = pkg_file.as_ref().to_string_lossy();
+    let mut archive = open_package(pkg_file.as_ref())?;
+
+    if let Some(entry) = io_ok!(archive.entries(), pkg_file_utf8).next() {
+        let mut entry = io_ok!(entry, pkg_file_utf8);
+        if io_ok!(entry.path(), pkg_file_utf8).as_ref() == Path::new(MANIFEST_NAME) {
+            let mut data = String::new();
+            io_ok!(entry.read_to_string(&mut data), pkg_file_utf8);
+            return Manifest::from_toml(&data);
+        }
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "[{}:{}] not an upkg package, {} is missing: {}",
+            file!(),
+            line!(),
+            MANIFEST_NAME,
+            pkg_file_utf8
+        ),
+    ))
+}
+
+fn unpack_err(kind: ErrorKind, path: &Path, reason: &str) -> Error {
+    Error::new(
+        kind,
+        format!(
+            "[{}:{}] can't install {}: {}",
+            file!(),
+            line!(),
+            path.to_string_lossy(),
+            reason
+        ),
+    )
+}
+
+// Removes the file or symlink at `path` so a package member can take its
+// place, a directory there is an error.
+fn make_room(path: &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => Err(unpack_err(
+            ErrorKind::AlreadyExists,
+            path,
+            "a directory is in the way",
+        )),
+        Ok(_) => {
+            io_ok!(fs::remove_file(path), path.to_string_lossy());
+            Ok(())
+        }
+        Err(_) => Ok(()),
+    }
+}
+
+fn unpack_entry<R: Read>(
+    entry: &mut tar::Entry<R>,
+    root: &Path,
+    rel_path: &Path,
+) -> std::io::Result<()> {
+    let path = root.join(rel_path);
+    let path_utf8 = path.to_string_lossy();
+    let header = entry.header();
+    let mode = io_ok!(header.mode(), path_utf8) & 0o7777;
+
+    // parents come first in a package, they may be symlinks into the root
+    // like `bin -> usr/bin`
+    if let Some(parent) = path.parent() {
+        if !parent.is_dir() {
+            return Err(unpack_err(
+                ErrorKind::NotFound,
+                &path,
+                "parent is not a directory",
+            ));
+        }
+        if !parent.is_subpath_of(root)? {
+            return Err(unpack_err(
+                ErrorKind::PermissionDenied,
+                &path,
+                "parent resolves outside of the install root",
+            ));
+        }
+    }
+
+    match header.entry_type() {
+        // an existing directory, or symlink to one, is kept as it is
+        tar::EntryType::Directory if path.is_dir() => (),
+        tar::EntryType::Directory => {
+            make_room(&path)?;
+            io_ok!(fs::create_dir(&path), path_utf8);
+            io_ok!(
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode)),
+                path_utf8
+            );
+        }
+        tar::EntryType::Symlink => {
+            let target = io_ok!(entry.link_name(), path_utf8).ok_or_else(|| {
+                unpack_err(ErrorKind::InvalidData, &path, "symlink without target")
+            })?;
+            make_room(&path)?;
+            io_ok!(
+                std::os::unix::fs::symlink(&target, &path),
+                "{} -> {}",
+                path_utf8,
+                target.to_string_lossy()
+            );
+        }
+        tar::EntryType::Regular => {
+            let mtime = io_ok!(header.mtime(), path_utf8);
+            make_room(&path)?;
+            let mut file = io_ok!(fs::File::create(&path), path_utf8);
+            io_ok!(std::io::copy(entry, &mut file), path_utf8);
+            io_ok!(
+                file.set_permissions(fs::Permissions::from_mode(mode)),
+                path_utf8
+            );
+            io_ok!(
+                file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime)),
+                path_utf8
+            );
+        }
+        kind => {
+            return Err(unpack_err(
+                ErrorKind::InvalidData,
+                &path,
+                &format!("unsupported entry type {:?}", kind),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Unpacks the file tree of `pkg_file` (everything but the manifest) into
+/// `root`. Like pacman, directories already in `root` keep their mode and
+/// symlinks to directories (`bin -> usr/bin`) are followed, only files and
+/// symlinks are replaced.
+pub fn unpack_package<P, R>(pkg_file: P, root: R) -> std::io::Result<()>
+where
+    P: AsRef<std::path::Path>,
+    R: AsRef<std::path::Path>,
+{
+    let root = root.as_ref();
+    let pkg_file_utf8 = pkg_file.as_ref().to_string_lossy();
+    let mut archive = open_package(pkg_file.as_ref())?;
+
+    io_ok!(fs::create_dir_all(root), root.to_string_lossy());
+    for entry in io_ok!(archive.entries(), pkg_file_utf8) {
+        let mut entry = io_ok!(entry, pkg_file_utf8);
+        let rel_path = sanitize_entry_path(&io_ok!(entry.path(), pkg_file_utf8))?;
+        if rel_path.as_os_str().is_empty() || rel_path == Path::new(MANIFEST_NAME) {
+            continue;
+        }
+        unpack_entry(&mut entry, root, &rel_path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+
+    fn test_package() -> Package {
+        let lua = Lua::new();
+        let pkg = lua
+            .load(
+                r#"return {
+                    pkg = { name = "hello", ver = "1.0", desc = "says hello", arch = "any" },
+                    depends = { "glibc" },
+                    source = {},
+                    checksum = {},
+                }"#,
+            )
+            .eval()
+            .unwrap();
+        lua.from_value(pkg).unwrap()
+    }
+
+    #[test]
+    fn test_package_round_trip() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("pkgfile-roundtrip");
+        let pkg_dir = dir.join("pkg");
+        fs::create_dir_all(pkg_dir.join("usr/bin")).unwrap();
+        fs::write(pkg_dir.join("usr/bin/hello"), b"#!/bin/sh\necho hello\n").unwrap();
+        fs::set_permissions(
+            pkg_dir.join("usr/bin/hello"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("hello", pkg_dir.join("usr/bin/hi")).unwrap();
+
+        let manifest = Manifest::from_staging(&test_package(), &pkg_dir, "tester").unwrap();
+        let paths: Vec<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["usr", "usr/bin", "usr/bin/hello", "usr/bin/hi"]);
+
+        let pkg_file = create_package(&manifest, &pkg_dir, &dir).unwrap();
+        assert_eq!(pkg_file, dir.join("hello-1.0-1-any.upkg.tar.zst"));
+
+        let read_back = read_manifest(&pkg_file).unwrap();
+        assert_eq!(read_back.files, manifest.files);
+        assert_eq!(read_back.packager, "tester");
+
+        let root = dir.join("root");
+        unpack_package(&pkg_file, &root).unwrap();
+        assert!(!root.join(MANIFEST_NAME).exists());
+        assert_eq!(
+            fs::read(root.join("usr/bin/hi")).unwrap(),
+            b"#!/bin/sh\necho hello\n"
+        );
+        let mode = fs::metadata(root.join("usr/bin/hello"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,408 @@
+use crate::lua::lua_types::*;
+use crate::pkg::db::*;
+use crate::pkg::manifest::*;
+use crate::*;
+
+use std::collections::HashSet;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RemoveOpts {
+    /// Also remove every package depending on one of the targets.
+    pub cascade: bool,
+    /// Also remove dependencies of the targets which were installed as a
+    /// dependency and are not needed by any remaining package.
+    pub recursive: bool,
+}
+
+fn satisfies(installed: &InstalledPackage, dep: &DepInfo) -> bool {
+    dep.dependency()
+        .is_ok_and(|dep| installed.package.satisfies(&dep))
+}
+
+fn is_satisfied(packages: &[InstalledPackage], removed: &HashSet<String>, dep: &DepInfo) -> bool {
+    packages
+        .iter()
+        .filter(|installed| !removed.contains(&installed.package.name))
+        .any(|installed| satisfies(installed, dep))
+}
+
+/// Remaining packages with a dependency that breaks once `removed` is gone,
+/// as `(package, dependency)` pairs.
+fn broken_deps<'a>(
+    packages: &'a [InstalledPackage],
+    removed: &HashSet<String>,
+) -> Vec<(&'a InstalledPackage, &'a DepInfo)> {
+    packages
+        .iter()
+        .filter(|installed| !removed.contains(&installed.package.name))
+        .flat_map(|installed| {
+            installed
+                .package
+                .depends
+                .iter()
+                .map(move |dep| (installed, dep))
+        })
+        .filter(|(_, dep)| !is_satisfied(packages, removed, dep))
+        .collect()
+}
+
+/// Works out which packages to remove for `targets`, in removal order.
+pub fn plan_removal(
+    packages: &[InstalledPackage],
+    targets: &[String],
+    opts: RemoveOpts,
+) -> std::io::Result<Vec<String>> {
+    let mut order: Vec<String> = Vec::new();
+    let mut removed: HashSet<String> = HashSet::new();
+
+    for target in targets {
+        if !packages
+            .iter()
+            .any(|installed| &installed.package.name == target)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "[{}:{}] package not installed: {}",
+                    file!(),
+                    line!(),
+                    target
+                ),
+            ));
+        }
+        if removed.insert(target.clone()) {
+            order.push(target.clone());
+        }
+    }
+
+    loop {
+        let broken = broken_deps(packages, &removed);
+        if broken.is_empty() {
+            break;
+        }
+        if !opts.cascade {
+            let reasons: Vec<String> = broken
+                .iter()
+                .map(|(installed, dep)| format!("{} depends on {}", installed.package.name, dep))
+                .collect();
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "[{}:{}] removal would break dependencies (use --cascade):\n  {}",
+                    file!(),
+                    line!(),
+                    reasons.join("\n  ")
+                ),
+            ));
+        }
+        // dependents have to go before the packages they depend on
+        for (installed, _) in broken {
+            if removed.insert(installed.package.name.clone()) {
+                order.insert(0, installed.package.name.clone());
+            }
+        }
+    }
+
+    if opts.recursive {
+        loop {
+            let removed_pkgs: Vec<&InstalledPackage> = packages
+                .iter()
+                .filter(|installed| removed.contains(&installed.package.name))
+                .collect();
+            let orphans: Vec<String> = packages
+                .iter()
+                .filter(|installed| {
+                    !removed.contains(&installed.package.name)
+                        && installed.reason == InstallReason::dependency
+                        && removed_pkgs.iter().any(|removed_pkg| {
+                            removed_pkg
+                                .package
+                                .depends
+                                .iter()
+                                .any(|dep| satisfies(installed, dep))
+                        })
+                })
+                .map(|installed| installed.package.name.clone())
+                .filter(|name| {
+                    let mut with_orphan = removed.clone();
+                    with_orphan.insert(name.clone());
+                    broken_deps(packages, &with_orphan).is_empty()
+                })
+                .collect();
+
+            if orphans.is_empty() {
+                break;
+            }
+            for orphan in orphans {
+                removed.insert(orphan.clone());
+                order.push(orphan);
+            }
+        }
+    }
+
+    Ok(order)
+}
+
+/// Deletes `files` below `root`, skipping every path listed in `keep`. Emptied
+/// directories are pruned, deepest first.
+pub fn remove_owned_files(
+    root: &std::path::Path,
+    files: &[FileEntry],
+    keep: &HashSet<&str>,
+) -> std::io::Result<()> {
+    for file in files.iter().filter(|file| file.kind != FileKind::dir) {
+        if keep.contains(file.path.as_str()) {
+            continue;
+        }
+        let path = root.join(&file.path);
+        match fs::remove_file(&path) {
+            Ok(()) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                println!("warning: {:?} already gone", path);
+            }
+            Err(err) => return Err(err).map_err(io_err_ctx!(path.to_string_lossy())),
+        }
+    }
+
+    let mut dirs: Vec<&FileEntry> = files
+        .iter()
+        .filter(|file| file.kind == FileKind::dir && !keep.contains(file.path.as_str()))
+        .collect();
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.path.matches('/').count()));
+
+    for dir in dirs {
+        let path = root.join(&dir.path);
+        // a symlinked directory like `bin -> usr/bin` came with the system
+        if fs::symlink_metadata(&path).is_ok_and(|meta| meta.is_symlink()) {
+            continue;
+        }
+        match fs::remove_dir(&path) {
+            Ok(()) => (),
+            Err(err)
+                if err.kind() == std::io::ErrorKind::DirectoryNotEmpty
+                    || err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).map_err(io_err_ctx!(path.to_string_lossy())),
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes the files and the database entry of the installed package `name`.
+/// Paths also owned by a package not listed in `removing` are kept.
+pub fn remove_installed(
+    db: &LocalDb,
+    root: &std::path::Path,
+    packages: &[InstalledPackage],
+    name: &str,
+    removing: &[String],
+) -> std::io::Result<InstalledPackage> {
+    let installed = packages
+        .iter()
+        .find(|installed| installed.package.name == name)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("[{}:{}] package not installed: {}", file!(), line!(), name),
+            )
+        })?;
+    // paths still claimed by packages that stay, e.g. shared directories
+    let keep: HashSet<&str> = packages
+        .iter()
+        .filter(|other| !removing.contains(&other.package.name))
+        .flat_map(|other| other.package.files.iter().map(|file| file.path.as_str()))
+        .collect();
+
+    println!("removing {}", installed.entry_name());
+    remove_owned_files(root, &installed.package.files, &keep)?;
+    db.remove(name)?;
+
+    Ok(installed.clone())
+}
+
+/// Removes `targets` (plus whatever `opts` pulls in) from `root` and `db`,
+/// returning the removed packages.
+pub fn remove_packages<R: AsRef<std::path::Path>>(
+    db: &LocalDb,
+    root: R,
+    targets: &[String],
+    opts: RemoveOpts,
+) -> std::io::Result<Vec<InstalledPackage>> {
+    let packages = db.packages()?;
+    let order = plan_removal(&packages, targets, opts)?;
+
+    order
+        .iter()
+        .map(|name| remove_installed(db, root.as_ref(), &packages, name, &order))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+
+    fn installed(name: &str, reason: InstallReason, depends: &[&str]) -> InstalledPackage {
+        InstalledPackage {
+            reason,
+            install_date: 0,
+            package: Manifest {
+                name: name.to_string(),
+                ver: String::from("1.0"),
+                rel: 1,
+                desc: String::new(),
+                arch: String::from("any"),
+                url: String::new(),
+                license: vec![],
+                groups: vec![],
+                depends: depends
+                    .iter()
+                    .map(|dep| DepInfo::Simple(dep.to_string()))
+                    .collect(),
+                opt_depends: vec![],
+                provides: vec![],
+                conflicts: vec![],
+                replaces: vec![],
+                packager: String::new(),
+                build_date: 0,
+                installed_size: 0,
+                files: vec![],
+            },
+        }
+    }
+
+    fn world() -> Vec<InstalledPackage> {
+        vec![
+            installed("app", InstallReason::explicit, &["lib"]),
+            installed("lib", InstallReason::dependency, &["base"]),
+            installed("base", InstallReason::dependency, &[]),
+            installed("tool", InstallReason::explicit, &["base"]),
+        ]
+    }
+
+    fn plan(targets: &[&str], opts: RemoveOpts) -> std::io::Result<Vec<String>> {
+        let targets: Vec<String> = targets.iter().map(|t| t.to_string()).collect();
+        plan_removal(&world(), &targets, opts)
+    }
+
+    #[test]
+    fn test_refuse_breaking_dependency() {
+        let err = plan(&["lib"], RemoveOpts::default()).unwrap_err();
+        assert!(err.to_string().contains("app depends on lib"));
+    }
+
+    #[test]
+    fn test_cascade_removes_dependents_first() {
+        let opts = RemoveOpts {
+            cascade: true,
+            recursive: false,
+        };
+        assert_eq!(plan(&["lib"], opts).unwrap(), ["app", "lib"]);
+    }
+
+    #[test]
+    fn test_recursive_keeps_shared_dependencies() {
+        let opts = RemoveOpts {
+            cascade: false,
+            recursive: true,
+        };
+        // base is still needed by tool
+        assert_eq!(plan(&["app"], opts).unwrap(), ["app", "lib"]);
+    }
+
+    #[test]
+    fn test_remove_prunes_empty_dirs() {
+        let root = scratch_dir("remove-prune");
+        fs::create_dir_all(root.join("usr/share/app")).unwrap();
+        fs::write(root.join("usr/share/app/data"), b"").unwrap();
+        fs::write(root.join("usr/share/other"), b"").unwrap();
+
+        let entry = |path: &str, kind: FileKind| FileEntry {
+            path: path.to_string(),
+            kind,
+            mode: 0o755,
+            size: 0,
+            sha256: None,
+            target: None,
+        };
+        let files = [
+            entry("usr", FileKind::dir),
+            entry("usr/share", FileKind::dir),
+            entry("usr/share/app", FileKind::dir),
+            entry("usr/share/app/data", FileKind::file),
+        ];
+        remove_owned_files(&root, &files, &HashSet::new()).unwrap();
+
+        assert!(!root.join("usr/share/app").exists());
+        // still holds a file of someone else
+        assert!(root.join("usr/share/other").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_remove_packages_with_cascade() {
+        let dir = scratch_dir("remove-cascade");
+        let root = dir.join("root");
+        let db = LocalDb::open(dir.join("db")).unwrap();
+
+        // `lib` and `app` share `usr`, `tool` also has a file in `usr/bin`
+        let packages: [(&str, InstallReason, &[&str], &str); 3] = [
+            ("lib", InstallReason::dependency, &[], "usr/lib/libfoo.so"),
+            ("app", InstallReason::explicit, &["lib"], "usr/bin/app"),
+            ("tool", InstallReason::explicit, &[], "usr/bin/tool"),
+        ];
+        let entry = |path: &str, kind| FileEntry {
+            path: path.to_string(),
+            kind,
+            mode: 0o755,
+            size: 0,
+            sha256: None,
+            target: None,
+        };
+        for (name, reason, depends, file) in packages {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, name).unwrap();
+
+            let mut pkg = installed(name, reason, depends);
+            let parent = std::path::Path::new(file).parent().unwrap();
+            pkg.package.files = vec![
+                entry("usr", FileKind::dir),
+                entry(&parent.to_string_lossy(), FileKind::dir),
+                entry(file, FileKind::file),
+            ];
+            db.add(&pkg).unwrap();
+        }
+
+        let targets = [String::from("lib")];
+        let err = remove_packages(&db, &root, &targets, RemoveOpts::default()).unwrap_err();
+        assert!(err.to_string().contains("app depends on lib"), "{}", err);
+        assert!(root.join("usr/lib/libfoo.so").exists());
+        assert_eq!(db.packages().unwrap().len(), 3);
+
+        let opts = RemoveOpts {
+            cascade: true,
+            recursive: false,
+        };
+        let removed: Vec<String> = remove_packages(&db, &root, &targets, opts)
+            .unwrap()
+            .into_iter()
+            .map(|installed| installed.package.name)
+            .collect();
+        assert_eq!(removed, ["app", "lib"]);
+
+        assert!(!root.join("usr/bin/app").exists());
+        assert!(!root.join("usr/lib").exists());
+        assert_eq!(fs::read(root.join("usr/bin/tool")).unwrap(), b"tool");
+        let left: Vec<String> = db
+            .packages()
+            .unwrap()
+            .into_iter()
+            .map(|installed| installed.package.name)
+            .collect();
+        assert_eq!(left, ["tool"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,426 @@
+use crate::lua::lua_types::*;
+use crate::pkg::db::*;
+use crate::pkg::manifest::*;
+use crate::pkg::package_file::*;
+use crate::version::*;
+use crate::*;
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+/// A built package that can be installed, usually a `.upkg.tar.zst` on disk.
+#[derive(Debug, Clone)]
+pub struct Available {
+    pub manifest: Manifest,
+    pub pkg_file: PathBuf,
+}
+
+impl Available {
+    pub fn from_file<P: AsRef<std::path::Path>>(pkg_file: P) -> std::io::Result<Available> {
+        Ok(Available {
+            manifest: read_manifest(pkg_file.as_ref())?,
+            pkg_file: pkg_file.as_ref().to_path_buf(),
+        })
+    }
+}
+
+/// Every package file in `dir`, half written `.part` files are skipped.
+pub fn scan_packages<P: AsRef<std::path::Path>>(dir: P) -> std::io::Result<Vec<Available>> {
+    let dir_utf8 = dir.as_ref().to_string_lossy();
+    let mut available = Vec::new();
+
+    for entry in io_ok!(fs::read_dir(dir.as_ref()), dir_utf8) {
+        let entry = io_ok!(entry, dir_utf8);
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if file_name.starts_with('.') || !file_name.ends_with(PACKAGE_SUFFIX) {
+            continue;
+        }
+        available.push(Available::from_file(entry.path())?);
+    }
+
+    available.sort_by(|lhs, rhs| lhs.pkg_file.cmp(&rhs.pkg_file));
+    Ok(available)
+}
+
+#[derive(Debug)]
+pub struct Step {
+    pub package: Available,
+    pub reason: InstallReason,
+}
+
+/// What installing a set of packages takes: the installed packages replaced by
+/// the new ones have to go first, then `install` in order, dependencies first.
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub remove: Vec<String>,
+    pub install: Vec<Step>,
+}
+
+fn resolve_err(title: &str, problems: &[String]) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!(
+            "[{}:{}] {}:\n  {}",
+            file!(),
+            line!(),
+            title,
+            problems.join("\n  ")
+        ),
+    )
+}
+
+/// Packages present once the plan went through, the planned ones shadow
+/// installed packages of the same name.
+fn final_world<'a>(
+    installed: &'a [InstalledPackage],
+    steps: &'a [Step],
+    removed: &HashSet<String>,
+) -> Vec<&'a Manifest> {
+    let planned: Vec<&Manifest> = steps.iter().map(|step| &step.package.manifest).collect();
+    installed
+        .iter()
+        .map(|installed| &installed.package)
+        .filter(|pkg| !removed.contains(&pkg.name))
+        .filter(|pkg| !planned.iter().any(|planned| planned.name == pkg.name))
+        .chain(planned.iter().copied())
+        .collect()
+}
+
+// Hint for an unsatisfied dependency, the versions around under that name.
+fn found_hint(dep: &Dependency, world: &[&Manifest], available: &[Available]) -> String {
+    let found: Vec<String> = world
+        .iter()
+        .copied()
+        .chain(available.iter().map(|available| &available.manifest))
+        .filter(|pkg| pkg.name == dep.name)
+        .map(|pkg| format!("{}-{}", pkg.name, pkg.version()))
+        .collect();
+
+    match found.is_empty() {
+        true => String::from(" (no package provides it)"),
+        false => format!(" (found {})", found.join(", ")),
+    }
+}
+
+// Dependencies first, ties keep the order the packages were planned in.
+fn install_order(steps: Vec<Step>) -> Vec<Step> {
+    fn visit(idx: usize, steps: &[Step], visited: &mut Vec<bool>, order: &mut Vec<usize>) {
+        if visited[idx] {
+            return;
+        }
+        visited[idx] = true;
+        for dep in &steps[idx].package.manifest.depends {
+            let Ok(dep) = dep.dependency() else { continue };
+            if let Some(dep_idx) = steps
+                .iter()
+                .position(|step| step.package.manifest.satisfies(&dep))
+            {
+                visit(dep_idx, steps, visited, order);
+            }
+        }
+        order.push(idx);
+    }
+
+    let mut visited = vec![false; steps.len()];
+    let mut order = Vec::new();
+    for idx in 0..steps.len() {
+        visit(idx, &steps, &mut visited, &mut order);
+    }
+
+    let mut steps: Vec<Option<Step>> = steps.into_iter().map(Some).collect();
+    order
+        .into_iter()
+        .filter_map(|idx| steps[idx].take())
+        .collect()
+}
+
+/// Works out how to install `targets` on top of `installed`, pulling missing
+/// dependencies (or a package providing them) from `available`. Installed
+/// packages matched by a `replaces` entry get removed. Unsatisfiable
+/// dependencies and conflicts are reported all at once.
+pub fn resolve(
+    installed: &[InstalledPackage],
+    available: &[Available],
+    targets: Vec<Available>,
+    reason: InstallReason,
+) -> std::io::Result<Plan> {
+    let mut steps: Vec<Step> = Vec::new();
+    for target in targets {
+        if !steps
+            .iter()
+            .any(|step| step.package.manifest.name == target.manifest.name)
+        {
+            steps.push(Step {
+                package: target,
+                reason,
+            });
+        }
+    }
+
+    let mut removed: HashSet<String> = HashSet::new();
+    let mut idx = 0;
+    while idx < steps.len() {
+        let manifest = steps[idx].package.manifest.clone();
+
+        for replaced in manifest
+            .replaces
+            .iter()
+            .filter_map(|dep| dep.dependency().ok())
+        {
+            for other in installed {
+                if other.package.name != manifest.name
+                    && replaced.satisfied_by(&other.package.name, Some(&other.package.version()))
+                {
+                    removed.insert(other.package.name.clone());
+                }
+            }
+        }
+
+        for dep in manifest
+            .depends
+            .iter()
+            .filter_map(|dep| dep.dependency().ok())
+        {
+            if final_world(installed, &steps, &removed)
+                .iter()
+                .any(|pkg| pkg.satisfies(&dep))
+            {
+                continue;
+            }
+            // the newest candidate wins, unsatisfiable ones are reported below
+            let candidate = available
+                .iter()
+                .filter(|candidate| candidate.manifest.satisfies(&dep))
+                .filter(|candidate| {
+                    !steps
+                        .iter()
+                        .any(|step| step.package.manifest.name == candidate.manifest.name)
+                })
+                .max_by(|lhs, rhs| lhs.manifest.version().cmp(&rhs.manifest.version()));
+            if let Some(candidate) = candidate {
+                steps.push(Step {
+                    package: candidate.clone(),
+                    reason: InstallReason::dependency,
+                });
+            }
+        }
+
+        idx += 1;
+    }
+
+    let world = final_world(installed, &steps, &removed);
+    let mut problems = Vec::new();
+
+    for pkg in &world {
+        for dep in &pkg.depends {
+            let dep = match dep.dependency() {
+                Ok(dep) => dep,
+                Err(err) => {
+                    problems.push(format!("{}: {}", pkg.name, err));
+                    continue;
+                }
+            };
+            if !world.iter().any(|other| other.satisfies(&dep)) {
+                problems.push(format!(
+                    "{} requires {}{}",
+                    pkg.name,
+                    dep,
+                    found_hint(&dep, &world, available)
+                ));
+            }
+        }
+    }
+
+    for step in &steps {
+        let pkg = &step.package.manifest;
+        for other in world.iter().filter(|other| other.name != pkg.name) {
+            let conflicts = |lhs: &Manifest, rhs: &Manifest| {
+                lhs.conflicts
+                    .iter()
+                    .filter_map(|dep| dep.dependency().ok())
+                    .any(|dep| rhs.satisfies(&dep))
+            };
+            if conflicts(pkg, other) || conflicts(other, pkg) {
+                let problem = format!("{} conflicts with {}", pkg.name, other.name);
+                let reverse = format!("{} conflicts with {}", other.name, pkg.name);
+                if !problems.contains(&reverse) {
+                    problems.push(problem);
+                }
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(resolve_err("cannot resolve dependencies", &problems));
+    }
+
+    let mut remove: Vec<String> = removed.into_iter().collect();
+    remove.sort();
+    Ok(Plan {
+        remove,
+        install: install_order(steps),
+    })
+}
+
+/// Fails with every dependency needed to build `pkg` which no installed
+/// package satisfies, `check_depends` only count when `Check()` runs.
+pub fn check_build_deps(
+    pkg: &Package,
+    installed: &[InstalledPackage],
+    check: bool,
+) -> std::io::Result<()> {
+    let mut fields = vec![
+        ("depends", &pkg.depends),
+        ("make_depends", &pkg.make_depends),
+    ];
+    if check {
+        fields.push(("check_depends", &pkg.check_depends));
+    }
+
+    let mut missing = Vec::new();
+    for (field, deps) in fields {
+        for dep in deps {
+            let dep = dep.dependency()?;
+            if !installed
+                .iter()
+                .any(|installed| installed.package.satisfies(&dep))
+            {
+                missing.push(format!("{} ({})", dep, field));
+            }
+        }
+    }
+
+    match missing.is_empty() {
+        true => Ok(()),
+        false => Err(resolve_err(
+            "missing dependencies, install them or pass --nodeps",
+            &missing,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(name: &str, ver: &str, fields: &[(&str, &[&str])]) -> Manifest {
+        let deps = |field: &str| -> Vec<DepInfo> {
+            fields
+                .iter()
+                .filter(|(name, _)| *name == field)
+                .flat_map(|(_, deps)| deps.iter().map(|dep| DepInfo::Simple(dep.to_string())))
+                .collect()
+        };
+        Manifest {
+            name: name.to_string(),
+            ver: ver.to_string(),
+            rel: 1,
+            desc: String::new(),
+            arch: String::from("any"),
+            url: String::new(),
+            license: vec![],
+            groups: vec![],
+            depends: deps("depends"),
+            opt_depends: vec![],
+            provides: deps("provides"),
+            conflicts: deps("conflicts"),
+            replaces: deps("replaces"),
+            packager: String::new(),
+            build_date: 0,
+            installed_size: 0,
+            files: vec![],
+        }
+    }
+
+    fn available(manifest: Manifest) -> Available {
+        Available {
+            pkg_file: PathBuf::from(manifest.package_file_name()),
+            manifest,
+        }
+    }
+
+    fn installed(manifest: Manifest) -> InstalledPackage {
+        InstalledPackage {
+            reason: InstallReason::explicit,
+            install_date: 0,
+            package: manifest,
+        }
+    }
+
+    fn names(plan: &Plan) -> Vec<&str> {
+        plan.install
+            .iter()
+            .map(|step| step.package.manifest.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_pulls_dependencies_first() {
+        let repo = [
+            available(manifest("libssl", "3.0", &[])),
+            available(manifest(
+                "rustls-shim",
+                "1.0",
+                &[("provides", &["ssl=3.1"])],
+            )),
+            available(manifest("curl", "8.0", &[("depends", &["ssl>=3.1"])])),
+        ];
+        let target = available(manifest("app", "1.0", &[("depends", &["curl"])]));
+
+        let plan = resolve(&[], &repo, vec![target], InstallReason::explicit).unwrap();
+        assert_eq!(names(&plan), ["rustls-shim", "curl", "app"]);
+        assert_eq!(plan.install[0].reason, InstallReason::dependency);
+        assert_eq!(plan.install[2].reason, InstallReason::explicit);
+    }
+
+    #[test]
+    fn test_reports_unsatisfiable_and_conflicts() {
+        let world = [
+            installed(manifest("lib", "1.0", &[])),
+            installed(manifest("old", "1.0", &[("depends", &["lib<2"])])),
+            installed(manifest("musl", "1.2", &[])),
+        ];
+        let repo = [available(manifest("zlib", "1.2", &[]))];
+        let target = available(manifest(
+            "lib",
+            "2.0",
+            &[("depends", &["zlib>=1.3"]), ("conflicts", &["musl"])],
+        ));
+
+        let err = resolve(&world, &repo, vec![target], InstallReason::explicit)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("old requires lib<2 (found lib-2.0-1)"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("lib requires zlib>=1.3 (found zlib-1.2-1)"),
+            "{}",
+            err
+        );
+        assert!(err.contains("lib conflicts with musl"), "{}", err);
+    }
+
+    #[test]
+    fn test_replaces_removes_installed() {
+        let world = [
+            installed(manifest("openssl", "1.1", &[])),
+            installed(manifest("curl", "8.0", &[("depends", &["openssl"])])),
+        ];
+        let target = available(manifest(
+            "libressl",
+            "3.8",
+            &[
+                ("provides", &["openssl"]),
+                ("replaces", &["openssl"]),
+                ("conflicts", &["openssl"]),
+            ],
+        ));
+
+        let plan = resolve(&world, &[], vec![target], InstallReason::explicit).unwrap();
+        assert_eq!(plan.remove, ["openssl"]);
+        assert_eq!(names(&plan), ["libressl"]);
+    }
+}
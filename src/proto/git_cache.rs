@@ -0,0 +1,259 @@
+use crate::*;
+
+use git2::*;
+use sha2::{Digest, Sha256};
+
+use std::path::{Path, PathBuf};
+
+static GIT_CACHE_DIR: &str = "git";
+static LOCK_SUFFIX: &str = ".lck";
+
+// Mirrors keep upstream branches as branches, the clones made from a mirror
+// see them as `origin/<branch>` again.
+const MIRROR_REFSPECS: [&str; 2] = ["+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"];
+
+/// Bare mirror of `url` below `cache_dir`: `git/<sha256 of url>.git`.
+pub fn mirror_dir(cache_dir: &Path, url: &str) -> PathBuf {
+    cache_dir
+        .join(GIT_CACHE_DIR)
+        .join(format!("{:x}.git", Sha256::digest(url.as_bytes())))
+}
+
+fn lock_path(mirror: &Path) -> PathBuf {
+    let mut lock_name = mirror.as_os_str().to_owned();
+    lock_name.push(LOCK_SUFFIX);
+    PathBuf::from(lock_name)
+}
+
+/// A mirror in the cache, locked against other upkg processes for as long as
+/// the handle is alive.
+pub struct Mirror {
+    pub path: PathBuf,
+    _lock: fs::File,
+}
+
+impl Mirror {
+    /// Locks the mirror of `url`, waiting for other upkg processes using it.
+    pub fn lock(cache_dir: &Path, url: &str) -> std::io::Result<Mirror> {
+        let path = mirror_dir(cache_dir, url);
+        let lock_path = lock_path(&path);
+        let git_dir = path.parent().unwrap();
+        io_ok!(fs::create_dir_all(git_dir), git_dir.to_string_lossy());
+
+        let lock = io_ok!(
+            fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&lock_path),
+            lock_path.to_string_lossy()
+        );
+        match lock.try_lock() {
+            Ok(()) => (),
+            Err(fs::TryLockError::WouldBlock) => {
+                println!("waiting for another upkg holding {:?}...", lock_path);
+                io_ok!(lock.lock(), lock_path.to_string_lossy());
+            }
+            Err(fs::TryLockError::Error(err)) => {
+                return Err(err).map_err(io_err_ctx!(lock_path.to_string_lossy()));
+            }
+        }
+
+        Ok(Mirror { path, _lock: lock })
+    }
+
+    /// Creates the mirror of `src` or fetches new upstream refs into it. A new
+    /// mirror is only `depth` commits deep, `unshallow` fetches the rest.
+    pub fn sync(
+        &self,
+        src: &SourceField,
+        rmt: &git_clone::RemoteOpts,
+        unshallow: bool,
+    ) -> Result<(), Error> {
+        let url = src.location.as_str();
+        let (repo, fresh) = match Repository::open_bare(&self.path) {
+            Ok(repo) => (repo, false),
+            Err(_) => {
+                println!("creating mirror of {} in: {:?}", url, self.path);
+                let repo = git_ok!(Repository::init_bare(&self.path));
+                git_ok!(repo.remote_with_fetch("origin", url, MIRROR_REFSPECS[0]));
+                git_ok!(repo.remote_add_fetch("origin", MIRROR_REFSPECS[1]));
+                (repo, true)
+            }
+        };
+
+        let mut remote = git_ok!(repo.find_remote("origin"));
+        if remote.url() != Some(url) {
+            git_ok!(repo.remote_set_url("origin", url));
+            remote = git_ok!(repo.find_remote("origin"));
+        }
+
+        if fresh {
+            // the clones check out what upstream HEAD points to
+            let conn = git_ok!(remote.connect_auth(
+                Direction::Fetch,
+                Some(git_clone::setup_rmt_callbacks(rmt)),
+                None
+            ));
+            if let Ok(head) = conn.default_branch() {
+                git_ok!(repo.set_head(&String::from_utf8_lossy(&head)));
+            }
+        }
+
+        let depth = match (fresh, unshallow) {
+            (_, true) => Some(i32::MAX),
+            (true, false) => src
+                .depth
+                .map(|depth| i32::try_from(depth).unwrap_or(i32::MAX)),
+            (false, false) => None,
+        };
+        println!("updating mirror of: {}", url);
+        git_ok!(git_clone::fetch_origin(&repo, &MIRROR_REFSPECS, depth, rmt));
+
+        // commits that are on no branch or tag only come in by id
+        if let CheckoutType::commit(id) = &src.checkout
+            && id.len() == 40
+            && repo.find_commit(git_ok!(Oid::from_str(id))).is_err()
+        {
+            println!("fetching commit: {}", id);
+            git_ok!(git_clone::fetch_origin(&repo, &[id], None, rmt));
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes the git mirrors of the cache, skipping those in use by another upkg.
+pub fn clean(cache_dir: &Path) -> std::io::Result<usize> {
+    let git_dir = cache_dir.join(GIT_CACHE_DIR);
+    if !git_dir.exists() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    for entry in io_ok!(fs::read_dir(&git_dir), git_dir.to_string_lossy()) {
+        let path = io_ok!(entry, git_dir.to_string_lossy()).path();
+        if path.extension().is_none_or(|ext| ext != "git") {
+            continue;
+        }
+
+        let lock_path = lock_path(&path);
+        let lock = io_ok!(
+            fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&lock_path),
+            lock_path.to_string_lossy()
+        );
+        if lock.try_lock().is_err() {
+            println!("skipping mirror in use: {:?}", path);
+            continue;
+        }
+
+        // the lock file stays, another upkg may be about to open it
+        println!("removing: {:?}", path);
+        io_ok!(fs::remove_dir_all(&path), path.to_string_lossy());
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, ConfigLayer};
+    use crate::test_util::{commit_files, scratch_dir};
+
+    fn source(url: &Path, checkout: CheckoutType) -> SourceField {
+        SourceField {
+            proto: Proto::git,
+            location: url.to_string_lossy().to_string(),
+            checkout,
+            repo_name: Some(String::from("clone")),
+            pin: None,
+            follow_latest: false,
+            depth: None,
+            single_branch: false,
+            filter: None,
+            submodules: Submodules::All(false),
+            auth: None,
+            patch: None,
+            apply_to: None,
+            strip: None,
+        }
+    }
+
+    #[test]
+    fn test_clones_go_through_the_mirror() {
+        let dir = scratch_dir("cache-mirror");
+        let cache_dir = dir.join("cache");
+        let config = Config::from_layer(ConfigLayer {
+            cache_dir: Some(cache_dir.clone()),
+            ..Default::default()
+        });
+        let origin = Repository::init(dir.join("origin")).unwrap();
+        let first = commit_files(&origin, &[("VERSION", "1")]);
+
+        let src = source(&dir.join("origin"), CheckoutType::none);
+        let repo =
+            git_clone::git_sync_with_remote(&src, dir.join("a"), Some(&config), None).unwrap();
+        assert_eq!(git_clone::head_commit(&repo).unwrap(), first);
+
+        let mirror = mirror_dir(&cache_dir, &src.location);
+        let origin_url = repo
+            .find_remote("origin")
+            .unwrap()
+            .url()
+            .unwrap()
+            .to_string();
+        assert_eq!(origin_url, mirror.to_string_lossy());
+
+        // new upstream commits come in through the mirror
+        let second = commit_files(&origin, &[("VERSION", "2")]);
+        let src = source(
+            &dir.join("origin"),
+            CheckoutType::commit(second.to_string()),
+        );
+        let repo =
+            git_clone::git_sync_with_remote(&src, dir.join("b"), Some(&config), None).unwrap();
+        assert_eq!(git_clone::head_commit(&repo).unwrap(), second);
+        let mirror_repo = Repository::open_bare(&mirror).unwrap();
+        assert!(mirror_repo.find_commit(second).is_ok());
+
+        // offline, clones come from the mirror and upstream is never asked
+        fs::rename(dir.join("origin"), dir.join("gone")).unwrap();
+        let offline = Config::from_layer(ConfigLayer {
+            cache_dir: Some(cache_dir.clone()),
+            offline: Some(true),
+            ..Default::default()
+        });
+        let repo =
+            git_clone::git_sync_with_remote(&src, dir.join("c"), Some(&offline), None).unwrap();
+        assert_eq!(git_clone::head_commit(&repo).unwrap(), second);
+        repo.remote_set_url("origin", "https://example.com/hello.git")
+            .unwrap();
+        let rmt = git_clone::RemoteOpts {
+            basename: String::from("clone"),
+            auth: Default::default(),
+            offline: true,
+        };
+        let err = git_clone::fetch_origin(&repo, &MIRROR_REFSPECS, None, &rmt)
+            .err()
+            .unwrap();
+        assert!(
+            err.message()
+                .contains("offline, not fetching clone from example.com")
+        );
+
+        // mirrors in use are left alone
+        let locked = Mirror::lock(&cache_dir, &src.location).unwrap();
+        assert_eq!(clean(&cache_dir).unwrap(), 0);
+        drop(locked);
+        assert_eq!(clean(&cache_dir).unwrap(), 1);
+        assert!(!mirror.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,9 +1,22 @@
+use crate::version::Version;
 use crate::*;
+use std::collections::HashMap;
+use std::path::Path;
 use std::sync::LazyLock;
 
+use crate::config::Config;
+use crate::proto::git_auth::GitAuth;
+
+use crypto_common::Output;
 use git2::*;
 use indicatif::*;
+use sha2::Digest;
 
+#[allow(
+    clippy::absurd_extreme_comparisons,
+    clippy::chars_next_cmp,
+    clippy::needless_return
+)]
 fn git_url_basename(repo: &str) -> String {
     let mut base_name = match repo.split_once("://") {
         Some((_, rhs)) => rhs,
@@ -106,14 +119,20 @@ fn checkout_branch(repo: &Repository, branch_name: &str, force: bool) -> Result<
     Ok(())
 }
 
-fn checkout_tag(repo: &Repository, tag_name: &str) -> Result<(), git2::Error> {
+fn checkout_tag(repo: &Repository, tag_name: &str, force: bool) -> Result<(), git2::Error> {
     // Resolve tag to object
     let obj = git_ok!(repo.revparse_single(&format!("refs/tags/{}", tag_name)));
     let commit = git_ok!(obj.peel_to_commit()); // peel in case it’s an annotated tag
     let tree = git_ok!(commit.tree());
 
     // Checkout files
-    git_ok!(repo.checkout_tree(tree.as_object(), None));
+    let mut opts = build::CheckoutBuilder::new();
+    if force {
+        opts.force();
+    } else {
+        opts.safe();
+    }
+    git_ok!(repo.checkout_tree(tree.as_object(), Some(&mut opts)));
 
     // Detach HEAD to this commit
     git_ok!(repo.set_head_detached(commit.id()));
@@ -121,6 +140,43 @@ fn checkout_tag(repo: &Repository, tag_name: &str) -> Result<(), git2::Error> {
     Ok(())
 }
 
+fn checkout_commit(repo: &Repository, commit: &Commit, force: bool) -> Result<(), Error> {
+    let mut opts = build::CheckoutBuilder::new();
+    if force {
+        opts.force();
+    } else {
+        opts.safe();
+    }
+    git_ok!(repo.checkout_tree(commit.as_object(), Some(&mut opts)));
+    git_ok!(repo.set_head_detached(commit.id()));
+
+    Ok(())
+}
+
+// Resolves `rev` to a commit. A commit id that isn't in the clone yet (e.g. not
+// on any branch or tag) is fetched from origin first.
+fn find_rev_commit<'r>(
+    repo: &'r Repository,
+    rev: &str,
+    rmt: &RemoteOpts,
+) -> Result<Commit<'r>, Error> {
+    if repo.revparse_single(rev).is_err() && is_commit_id(rev) {
+        if rev.len() < 40 {
+            return Err(Error::from_str(&format!(
+                "[{}:{}] commit {} is not in the clone, use the full 40 digit id to fetch it",
+                file!(),
+                line!(),
+                rev
+            )));
+        }
+
+        println!("fetching commit: {}", rev);
+        git_ok!(fetch_origin(repo, &[rev], None, rmt));
+    }
+
+    Ok(git_ok!(git_ok!(repo.revparse_single(rev)).peel_to_commit()))
+}
+
 fn latest_tag_by_creation(repo: &Repository) -> Result<Option<String>, Error> {
     let tag_names = git_ok!(repo.tag_names(None));
     let mut latest: Option<(String, i64)> = None;
@@ -139,8 +195,12 @@ fn latest_tag_by_creation(repo: &Repository) -> Result<Option<String>, Error> {
                 0
             };
 
-            match latest {
-                Some((_, ts)) if ts >= tag_time => {}
+            // tags created within the same second go by version
+            match &latest {
+                Some((name, ts))
+                    if *ts > tag_time
+                        || (*ts == tag_time
+                            && Version::from_tag(name) >= Version::from_tag(tag_name)) => {}
                 _ => latest = Some((tag_name.to_string(), tag_time)),
             }
         }
@@ -149,133 +209,455 @@ fn latest_tag_by_creation(repo: &Repository) -> Result<Option<String>, Error> {
     Ok(latest.map(|(name, _)| name))
 }
 
+// Refspecs fetching all branches and tags. Tags are forced so a tag moved
+// upstream shows up (and fails verification).
+const ALL_REFSPECS: [&str; 2] = [
+    "refs/heads/*:refs/remotes/origin/*",
+    "+refs/tags/*:refs/tags/*",
+];
+
+// What to fetch for `src`, with `single_branch` only what gets checked out.
+fn fetch_refspecs(src: &SourceField) -> Vec<String> {
+    let mut refspecs: Vec<String> = ALL_REFSPECS.iter().map(|spec| spec.to_string()).collect();
+    if src.single_branch {
+        match &src.checkout {
+            CheckoutType::tag(tag) if !src.follow_latest => {
+                refspecs = vec![format!("+refs/tags/{0}:refs/tags/{0}", tag)];
+            }
+            CheckoutType::branch(branch) => {
+                refspecs = vec![format!("+refs/heads/{0}:refs/remotes/origin/{0}", branch)];
+            }
+            CheckoutType::commit(id) if id.len() == 40 => refspecs = vec![id.clone()],
+            CheckoutType::none => refspecs.clear(),
+            _ => (),
+        }
+    }
+    if let CheckoutType::none = src.checkout {
+        refspecs.push(String::from("+HEAD:refs/remotes/origin/HEAD"));
+    }
+    refspecs
+}
+
+// Fetches `refspecs` from origin. Transports without shallow support (like the
+// local one) get a full fetch instead of failing. Offline only local remotes
+// (like a mirror in the cache) are fetched from.
+pub fn fetch_origin<Spec: AsRef<str> + IntoCString + Clone>(
+    repo: &Repository,
+    refspecs: &[Spec],
+    depth: Option<i32>,
+    rmt: &RemoteOpts,
+) -> Result<(), Error> {
+    let mut remote = git_ok!(repo.find_remote("origin"));
+    if rmt.offline
+        && let Some(host) = remote.url().and_then(git_auth::url_host)
+    {
+        return Err(Error::from_str(&format!(
+            "[{}:{}] offline, not fetching {} from {}",
+            file!(),
+            line!(),
+            rmt.basename,
+            host
+        )));
+    }
+    let mut fetch = |depth: Option<i32>| {
+        let mut fetch_opts = FetchOptions::new();
+        fetch_opts.remote_callbacks(setup_rmt_callbacks(rmt));
+        // auto following tags never updates existing ones, the refspecs do
+        fetch_opts.download_tags(AutotagOption::None);
+        if let Some(depth) = depth {
+            fetch_opts.depth(depth);
+        }
+        remote.fetch(refspecs, Some(&mut fetch_opts), None)
+    };
+
+    match fetch(depth) {
+        Err(err) if depth.is_some() && err.message().contains("shallow") => {
+            println!("{}, fetching the full history", err.message());
+            git_ok!(fetch(None));
+        }
+        res => git_ok!(res),
+    }
+
+    Ok(())
+}
+
+pub fn fetch_depth(src: &SourceField) -> Option<i32> {
+    src.depth
+        .map(|depth| i32::try_from(depth).unwrap_or(i32::MAX))
+}
+
+// Fetches into the clone at `clone_path`, pointing its origin at `url` first.
 fn fetch_repo<RepoPath: AsRef<std::path::Path>>(
+    src: &SourceField,
     url: &str,
+    depth: Option<i32>,
     clone_path: RepoPath,
-    basename: String,
+    rmt: &RemoteOpts,
 ) -> Result<Repository, Error> {
     let repo = git_ok!(Repository::open(&clone_path));
+    match repo.find_remote("origin") {
+        Ok(remote) if remote.url() == Some(url) => (),
+        Ok(_) => git_ok!(repo.remote_set_url("origin", url)),
+        Err(_) => drop(git_ok!(repo.remote("origin", url))),
+    }
 
-    {
+    git_ok!(fetch_origin(&repo, &fetch_refspecs(src), depth, rmt));
+
+    Ok(repo)
+}
+
+fn clone_repo<RepoPath: AsRef<std::path::Path>>(
+    src: &SourceField,
+    url: &str,
+    depth: Option<i32>,
+    clone_path: RepoPath,
+    rmt: &RemoteOpts,
+) -> Result<Repository, Error> {
+    if depth.is_none() && !src.single_branch {
         let mut fetch_opts = FetchOptions::new();
-        fetch_opts.remote_callbacks(setup_rmt_callbacks(basename));
+        fetch_opts.remote_callbacks(setup_rmt_callbacks(rmt));
 
-        let mut remote = git_ok!(
-            repo.find_remote("origin")
-                .or_else(|_| repo.remote("origin", url))
-        );
+        let mut repo_handle = build::RepoBuilder::new();
+        repo_handle.fetch_options(fetch_opts);
 
-        git_ok!(remote.fetch(
-            &["refs/heads/*:refs/remotes/origin/*"],
-            Some(&mut fetch_opts),
-            None,
-        ));
+        return repo_handle
+            .clone(url, clone_path.as_ref())
+            .map_err(git_err_ctx!());
     }
 
+    // a clone fetches the default branch, which may not be wanted here
+    let repo = git_ok!(Repository::init(&clone_path));
+    git_ok!(repo.remote("origin", url));
+    git_ok!(fetch_origin(&repo, &fetch_refspecs(src), depth, rmt));
+
     Ok(repo)
 }
 
-fn clone_repo<RepoPath: AsRef<std::path::Path>>(
+fn repo_basename(url: &str, repo_name: Option<&str>) -> String {
+    match repo_name {
+        Some(val) => val.to_string(),
+        None => git_url_basename(url),
+    }
+}
+
+/// Directory `git_sync_with_remote` clones `url` into below `path`.
+pub fn repo_dir<RepoPath: AsRef<std::path::Path>>(
     url: &str,
-    clone_path: RepoPath,
-    basename: String,
-) -> Result<Repository, Error> {
-    let mut fetch_opts = FetchOptions::new();
-    fetch_opts.remote_callbacks(setup_rmt_callbacks(basename));
+    path: RepoPath,
+    repo_name: Option<&str>,
+) -> std::path::PathBuf {
+    path.as_ref().join(repo_basename(url, repo_name))
+}
+
+/// Hashes the tree of HEAD as a stream of `<kind> <mode> <path>\0<len>\0<data>`
+/// records in git's tree order, `data` being the blob content or the commit id
+/// the superproject records for a submodule. A checked out submodule that has
+/// that commit is followed by the tree of it. Only git objects go in, so the
+/// digest is the same for every clone and doesn't depend on the working tree
+/// state, not even on where the submodule HEAD has moved.
+pub fn tree_checksum<ShaType: Digest>(repo: &Repository) -> Result<Output<ShaType>, Error> {
+    let mut hasher = ShaType::new();
+    let tree = git_ok!(git_ok!(repo.head()).peel_to_tree());
+    git_ok!(hash_tree(repo, &tree, "", &mut hasher));
+    Ok(hasher.finalize())
+}
 
-    let mut repo_handle = build::RepoBuilder::new();
-    repo_handle.fetch_options(fetch_opts);
+fn hash_tree<ShaType: Digest>(
+    repo: &Repository,
+    tree: &Tree,
+    prefix: &str,
+    hasher: &mut ShaType,
+) -> Result<(), Error> {
+    let mut entries: Vec<(String, Oid, i32, Option<ObjectType>)> = Vec::new();
+    git_ok!(tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if entry.kind() != Some(ObjectType::Tree) {
+            let path = format!("{}{}", root, String::from_utf8_lossy(entry.name_bytes()));
+            entries.push((path, entry.id(), entry.filemode(), entry.kind()));
+        }
+        TreeWalkResult::Ok
+    }));
+
+    for (path, oid, mode, kind) in entries {
+        let (kind, data) = match kind {
+            Some(ObjectType::Blob) => ("blob", git_ok!(repo.find_blob(oid)).content().to_vec()),
+            _ => ("commit", oid.to_string().into_bytes()),
+        };
+        let full_path = format!("{}{}", prefix, path);
+        hasher.update(format!("{} {:06o} {}\0{}\0", kind, mode, full_path, data.len()).as_bytes());
+        hasher.update(&data);
+
+        if kind == "commit"
+            && let Ok(sub_repo) = repo
+                .find_submodule(&path)
+                .and_then(|submodule| submodule.open())
+            && let Ok(commit) = sub_repo.find_commit(oid)
+        {
+            let sub_tree = git_ok!(commit.tree());
+            git_ok!(hash_tree(
+                &sub_repo,
+                &sub_tree,
+                &format!("{}/", full_path),
+                hasher
+            ));
+        }
+    }
 
-    repo_handle
-        .clone(url, clone_path.as_ref())
-        .map_err(git_err_ctx!())
+    Ok(())
 }
 
-static SEMVER_RE: LazyLock<regex::Regex> = LazyLock::new(|| regex::Regex::new(r"\d+").unwrap());
+// Inits and updates the submodules of `repo` selected by `submodules`, along
+// with everything nested in them.
+fn update_submodules(
+    repo: &Repository,
+    submodules: &Submodules,
+    rmt: &RemoteOpts,
+    hosts: &HashMap<String, GitAuth>,
+    force: bool,
+) -> Result<(), Error> {
+    let mut found = Vec::new();
+    for mut submodule in git_ok!(repo.submodules()) {
+        let path = submodule.path().to_string_lossy().to_string();
+        if !submodules.includes(&path) {
+            continue;
+        }
+        println!("updating submodule: {}", path);
+
+        let mut checkout_opts = build::CheckoutBuilder::new();
+        if force {
+            checkout_opts.force();
+        } else {
+            checkout_opts.safe();
+        }
+        // the submodule goes with the credentials of its own host
+        let sub_rmt = RemoteOpts {
+            basename: format!("{}/{}", rmt.basename, path),
+            auth: git_auth::auth_for_url(None, hosts, submodule.url().unwrap_or_default()),
+            offline: rmt.offline,
+        };
+        if rmt.offline && submodule.open().is_err() {
+            return Err(Error::from_str(&format!(
+                "[{}:{}] offline, submodule {} isn't cloned yet",
+                file!(),
+                line!(),
+                path
+            )));
+        }
+        let mut fetch_opts = FetchOptions::new();
+        fetch_opts.remote_callbacks(setup_rmt_callbacks(&sub_rmt));
+
+        let mut update_opts = SubmoduleUpdateOptions::new();
+        update_opts
+            .checkout(checkout_opts)
+            .fetch(fetch_opts)
+            .allow_fetch(!rmt.offline);
+        git_ok!(submodule.update(true, Some(&mut update_opts)));
+
+        let sub_repo = git_ok!(submodule.open());
+        git_ok!(update_submodules(
+            &sub_repo,
+            &Submodules::All(true),
+            &sub_rmt,
+            hosts,
+            force
+        ));
+        found.push(path);
+    }
+
+    if let Submodules::Paths(paths) = submodules
+        && let Some(missing) = paths
+            .iter()
+            .find(|path| !found.contains(&path.trim_end_matches('/').to_string()))
+    {
+        return Err(Error::from_str(&format!(
+            "[{}:{}] no submodule at: {}",
+            file!(),
+            line!(),
+            missing
+        )));
+    }
 
-fn normalize_tag_to_semver(tag: &str) -> Vec<u32> {
-    // Matches consecutive digits
-    return SEMVER_RE
-        .find_iter(tag)
-        .filter_map(|m| m.as_str().parse::<u32>().ok())
-        .collect();
+    Ok(())
+}
+
+/// Id of the commit checked out in `repo`.
+pub fn head_commit(repo: &Repository) -> Result<Oid, Error> {
+    Ok(git_ok!(git_ok!(repo.head()).peel_to_commit()).id())
+}
+
+// The tag to check out for `tag`: the tag itself, or with `follow_latest` the
+// newest tag of the repo if that one is a later version.
+pub fn target_tag(repo: &Repository, tag: &str, follow_latest: bool) -> Result<String, Error> {
+    if follow_latest && let Some(new_tag) = git_ok!(latest_tag_by_creation(repo)) {
+        if Version::from_tag(tag) < Version::from_tag(&new_tag) {
+            return Ok(new_tag);
+        }
+        println!("not following, old tag: {}, new tag: {}", tag, new_tag);
+    }
+
+    Ok(tag.to_string())
+}
+
+// Checks out what `src` asks for, `force` throws away local changes of an
+// existing clone.
+fn checkout_source(
+    repo: &Repository,
+    src: &SourceField,
+    rmt: &RemoteOpts,
+    force: bool,
+) -> Result<(), Error> {
+    match &src.checkout {
+        CheckoutType::tag(tag) => {
+            let tag = git_ok!(target_tag(repo, tag, src.follow_latest));
+            println!("checkout to tag: {}", tag);
+            git_ok!(checkout_tag(repo, &tag, force));
+        }
+        CheckoutType::branch(branch) if force => {
+            let remote_ref = format!("refs/remotes/origin/{}", branch);
+
+            let target_ref_id = git_ok!(repo.refname_to_id(&remote_ref));
+            let target_commit = git_ok!(repo.find_commit(target_ref_id));
+
+            println!("updating HEAD to branch: {}", branch);
+            git_ok!(repo.reset(target_commit.as_object(), ResetType::Hard, None));
+        }
+        CheckoutType::branch(branch) => {
+            println!("checkout to branch: {}", &branch);
+            git_ok!(git_clone::checkout_branch(repo, branch, force));
+        }
+        CheckoutType::commit(rev) | CheckoutType::rev(rev) => {
+            let commit = git_ok!(find_rev_commit(repo, rev, rmt));
+            println!("checkout to commit: {}", commit.id());
+            git_ok!(checkout_commit(repo, &commit, force));
+        }
+        // a fresh single branch or shallow clone has no HEAD yet
+        CheckoutType::none if repo.head().is_err() => {
+            let head_id = git_ok!(repo.refname_to_id("refs/remotes/origin/HEAD"));
+            git_ok!(checkout_commit(
+                repo,
+                &git_ok!(repo.find_commit(head_id)),
+                force
+            ));
+        }
+        CheckoutType::none => (),
+    }
+
+    Ok(())
 }
 
+/// Clones the git source `src` below `path`, or fetches into an existing clone,
+/// and checks out what `src` asks for along with its submodules. A shallow clone
+/// missing the history for that is deepened to a full one.
+///
+/// A `locked` commit id is checked out instead of what the tag or branch
+/// resolves to now.
+///
+/// With a `config` upstream is fetched into a bare mirror in its cache dir,
+/// which the clone below `path` then fetches from, and the credentials it has
+/// for the host of a remote are used. Offline only the mirror and existing
+/// clones are used.
 pub fn git_sync_with_remote<RepoPath: AsRef<std::path::Path>>(
-    url: &str,
+    src: &SourceField,
     path: RepoPath,
-    repo_name: Option<&str>,
-    checkout: &CheckoutType,
+    config: Option<&Config>,
+    locked: Option<&str>,
 ) -> Result<Repository, Error> {
-    let basename = match repo_name {
-        Some(val) => val.to_string(),
-        None => git_url_basename(&url),
+    let url = src.location.as_str();
+    let no_hosts = HashMap::new();
+    let hosts = config.map_or(&no_hosts, |config| &config.git_auth);
+    let rmt = RemoteOpts {
+        basename: repo_basename(url, src.repo_name.as_deref()),
+        auth: git_auth::auth_for_url(src.auth.as_ref(), hosts, url),
+        offline: config.is_some_and(|config| config.offline),
     };
     println!("attempting to clone: {url}");
 
-    let clone_path = path.as_ref().join(&basename);
-    println!("clone path: {:?}", clone_path);
-
-    if clone_path.exists() {
-        println!("path exists, trying to sync repo with remote...");
-        let repo = git_ok!(fetch_repo(url, clone_path, basename));
-
-        {
-            match checkout {
-                CheckoutType::tag(tag) => {
-                    let norm_tag = normalize_tag_to_semver(&tag);
-
-                    if let Some(new_tag) = git_ok!(latest_tag_by_creation(&repo)) {
-                        let new_tag_norm = normalize_tag_to_semver(&new_tag);
-                        if norm_tag < new_tag_norm {
-                            println!("updating HEAD to tag: {}", new_tag);
-                            git_ok!(checkout_tag(&repo, &new_tag));
-                        } else {
-                            println!("not updating HEAD, old tag: {}, new tag: {}", tag, new_tag);
-                        }
-                    }
-                }
-                CheckoutType::branch(branch) => {
-                    let remote_ref = format!("refs/remotes/origin/{}", branch);
-
-                    let target_ref_id = git_ok!(repo.refname_to_id(&remote_ref));
-                    let target_commit = git_ok!(repo.find_commit(target_ref_id));
-
-                    println!("updating HEAD to branch: {}", branch);
-                    git_ok!(repo.reset(target_commit.as_object(), ResetType::Hard, None));
-                }
-                CheckoutType::none => (),
+    let mirror = match config {
+        Some(config) => {
+            let mirror = git_cache::Mirror::lock(&config.cache_dir, url)
+                .map_err(|err| Error::from_str(&err.to_string()))?;
+            if !rmt.offline {
+                git_ok!(mirror.sync(src, &rmt, false));
             }
+            Some(mirror)
         }
+        None => None,
+    };
+    // the mirror is local, no point in shallow fetches from it
+    let (origin_url, depth) = match &mirror {
+        Some(mirror) => (mirror.path.to_string_lossy().to_string(), None),
+        None => (url.to_string(), fetch_depth(src)),
+    };
 
-        Ok(repo)
+    let clone_path = repo_dir(url, path, src.repo_name.as_deref());
+    println!("clone path: {:?}", clone_path);
+
+    let exists = clone_path.exists();
+    let repo = if exists && rmt.offline && !Path::new(&origin_url).exists() {
+        println!("offline, using the existing clone as is");
+        git_ok!(Repository::open(&clone_path))
+    } else if exists {
+        println!("path exists, trying to sync repo with remote...");
+        git_ok!(fetch_repo(src, &origin_url, depth, clone_path, &rmt))
     } else {
         println!("trying to clone repo...");
+        git_ok!(clone_repo(src, &origin_url, depth, clone_path, &rmt))
+    };
 
-        let repo_handle = git_ok!(clone_repo(url, clone_path, basename));
-        match checkout {
-            CheckoutType::tag(tag) => {
-                println!("checkout to tag: {}", tag);
-                if let Some(new_tag) = git_ok!(latest_tag_by_creation(&repo_handle)) {
-                    git_ok!(checkout_tag(&repo_handle, &new_tag));
-                }
-            }
-            CheckoutType::branch(branch) => {
-                println!("checkout to branch: {}", &branch);
-                git_ok!(git_clone::checkout_branch(&repo_handle, &branch, false));
-            }
-            CheckoutType::none => (),
+    if let Err(err) = checkout_source(&repo, src, &rmt, exists) {
+        let shallow_mirror = mirror.as_ref().is_some_and(|mirror| {
+            Repository::open_bare(&mirror.path).is_ok_and(|m| m.is_shallow())
+        });
+        if !repo.is_shallow() && !shallow_mirror {
+            return Err(err);
         }
+        println!("{}, fetching the full history", err.message());
+        if let Some(mirror) = &mirror {
+            git_ok!(mirror.sync(src, &rmt, true));
+        }
+        let depth = mirror.is_none().then_some(i32::MAX);
+        git_ok!(fetch_origin(&repo, &ALL_REFSPECS, depth, &rmt));
+        git_ok!(checkout_source(&repo, src, &rmt, exists));
+    }
 
-        Ok(repo_handle)
+    if let Some(locked) = locked
+        && git_ok!(head_commit(&repo)).to_string() != locked
+    {
+        println!(
+            "{} moved to {} upstream, keeping the locked commit {} (upkg update refreshes it)",
+            rmt.basename,
+            git_ok!(head_commit(&repo)),
+            locked
+        );
+        let commit = git_ok!(find_rev_commit(&repo, locked, &rmt));
+        git_ok!(checkout_commit(&repo, &commit, true));
+    }
+
+    if !matches!(src.submodules, Submodules::All(false)) {
+        git_ok!(update_submodules(
+            &repo,
+            &src.submodules,
+            &rmt,
+            hosts,
+            exists
+        ));
     }
+
+    Ok(repo)
 }
 
 static SIDEBAND_PROGRESS_RE: LazyLock<regex::Regex> = LazyLock::new(|| {
     regex::Regex::new(r"^(Counting|Compressing) objects:\s+(\d+)% \((\d+)/(\d+)\)").unwrap()
 });
 
-fn setup_rmt_callbacks<'a>(basename: String) -> RemoteCallbacks<'a> {
+/// Label of the progress bar and credentials for talking to a remote.
+pub struct RemoteOpts {
+    pub basename: String,
+    pub auth: GitAuth,
+    pub offline: bool,
+}
+
+#[allow(clippy::collapsible_if)]
+pub fn setup_rmt_callbacks<'a>(rmt: &RemoteOpts) -> RemoteCallbacks<'a> {
     let pb = ProgressBar::new(100);
     pb.set_style(
         ProgressStyle::default_bar()
@@ -283,7 +665,7 @@ fn setup_rmt_callbacks<'a>(basename: String) -> RemoteCallbacks<'a> {
             .unwrap()
             .progress_chars("=> "),
     );
-    pb.set_prefix(basename.to_owned());
+    pb.set_prefix(rmt.basename.to_owned());
     pb.set_message("Git Clone");
 
     let pb_rc = std::rc::Rc::new(pb);
@@ -321,12 +703,14 @@ fn setup_rmt_callbacks<'a>(basename: String) -> RemoteCallbacks<'a> {
         true
     });
 
+    git_auth::add_credentials(&mut callbacks, rmt.auth.clone());
     callbacks
 }
 
 #[cfg(test)]
 mod tests {
     use super::git_url_basename;
+    use crate::test_util::{commit_files, scratch_dir};
 
     #[test]
     fn test_https_url_with_git_suffix() {
@@ -390,4 +774,200 @@ mod tests {
     fn test_empty_string() {
         git_url_basename("");
     }
+
+    #[test]
+    fn test_tree_checksum_follows_head_only() {
+        use sha2::Sha256;
+
+        let dir = scratch_dir("git-treehash");
+        let repo = git2::Repository::init(dir.join("repo")).unwrap();
+        let first = commit_files(
+            &repo,
+            &[("README", "hello"), ("src/main.rs", "fn main() {}")],
+        );
+        let digest = super::tree_checksum::<Sha256>(&repo).unwrap();
+        assert_eq!(super::head_commit(&repo).unwrap(), first);
+
+        // a clone hashes the same, local edits don't count
+        let clone =
+            git2::Repository::clone(dir.join("repo").to_str().unwrap(), dir.join("clone")).unwrap();
+        std::fs::write(dir.join("clone/README"), "edited").unwrap();
+        assert_eq!(super::tree_checksum::<Sha256>(&clone).unwrap(), digest);
+
+        commit_files(&repo, &[("README", "moved")]);
+        assert_ne!(super::tree_checksum::<Sha256>(&repo).unwrap(), digest);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn tag_head(repo: &git2::Repository, tag: &str) {
+        let head = repo.head().unwrap().peel(git2::ObjectType::Commit).unwrap();
+        repo.tag_lightweight(tag, &head, true).unwrap();
+    }
+
+    fn git_source(url: &std::path::Path, tag: &str, follow_latest: bool) -> super::SourceField {
+        super::SourceField {
+            proto: super::Proto::git,
+            location: url.to_string_lossy().to_string(),
+            checkout: super::CheckoutType::tag(tag.to_string()),
+            repo_name: Some(String::from("clone")),
+            pin: None,
+            follow_latest,
+            depth: None,
+            single_branch: false,
+            filter: None,
+            submodules: super::Submodules::All(false),
+            auth: None,
+            patch: None,
+            apply_to: None,
+            strip: None,
+        }
+    }
+
+    #[test]
+    fn test_sync_checks_out_requested_tag() {
+        let dir = scratch_dir("git-tags");
+        let origin = git2::Repository::init(dir.join("origin")).unwrap();
+        let v1 = commit_files(&origin, &[("VERSION", "1")]);
+        tag_head(&origin, "v1.0");
+        let v2 = commit_files(&origin, &[("VERSION", "2")]);
+        tag_head(&origin, "v2.0");
+
+        let exact = git_source(&dir.join("origin"), "v1.0", false);
+        let repo = super::git_sync_with_remote(&exact, dir.join("exact"), None, None).unwrap();
+        assert_eq!(super::head_commit(&repo).unwrap(), v1);
+
+        let latest = git_source(&dir.join("origin"), "v1.0", true);
+        let repo = super::git_sync_with_remote(&latest, dir.join("latest"), None, None).unwrap();
+        assert_eq!(super::head_commit(&repo).unwrap(), v2);
+
+        // moving the tag upstream is picked up by the next sync
+        let moved = commit_files(&origin, &[("VERSION", "1.1")]);
+        tag_head(&origin, "v1.0");
+        let repo = super::git_sync_with_remote(&exact, dir.join("exact"), None, None).unwrap();
+        assert_eq!(super::head_commit(&repo).unwrap(), moved);
+        assert_eq!(
+            std::fs::read(dir.join("exact/clone/VERSION")).unwrap(),
+            b"1.1"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sync_checks_out_commit_and_rev() {
+        let dir = scratch_dir("git-commits");
+        let origin = git2::Repository::init(dir.join("origin")).unwrap();
+        let first = commit_files(&origin, &[("VERSION", "1")]);
+        let second = commit_files(&origin, &[("VERSION", "2")]);
+        commit_files(&origin, &[("VERSION", "3")]);
+
+        let mut src = git_source(&dir.join("origin"), "unused", false);
+        src.checkout = super::CheckoutType::commit(first.to_string());
+        let repo = super::git_sync_with_remote(&src, &dir, None, None).unwrap();
+        assert_eq!(super::head_commit(&repo).unwrap(), first);
+        assert_eq!(std::fs::read(dir.join("clone/VERSION")).unwrap(), b"1");
+
+        src.checkout =
+            super::CheckoutType::rev(format!("{}^", origin.head().unwrap().target().unwrap()));
+        let repo = super::git_sync_with_remote(&src, &dir, None, None).unwrap();
+        assert_eq!(super::head_commit(&repo).unwrap(), second);
+
+        src.checkout = super::CheckoutType::commit(String::from("deadbeef"));
+        assert!(super::git_sync_with_remote(&src, &dir, None, None).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_single_branch_shallow_clone() {
+        let dir = scratch_dir("git-shallow");
+        let origin = git2::Repository::init(dir.join("origin")).unwrap();
+        commit_files(&origin, &[("VERSION", "1")]);
+        tag_head(&origin, "v1.0");
+        let head = commit_files(&origin, &[("VERSION", "2")]);
+        let default_branch = origin.head().unwrap().shorthand().unwrap().to_string();
+        origin
+            .branch("dev", &origin.find_commit(head).unwrap(), false)
+            .unwrap();
+
+        // the local transport can't do shallow fetches, so this is a full one
+        let mut src = git_source(&dir.join("origin"), "v1.0", false);
+        src.depth = Some(1);
+        src.single_branch = true;
+        let repo = super::git_sync_with_remote(&src, dir.join("tag"), None, None).unwrap();
+        assert_eq!(std::fs::read(dir.join("tag/clone/VERSION")).unwrap(), b"1");
+        assert!(repo.find_reference("refs/remotes/origin/dev").is_err());
+
+        src.checkout = super::CheckoutType::branch(String::from("dev"));
+        let repo = super::git_sync_with_remote(&src, dir.join("branch"), None, None).unwrap();
+        assert_eq!(super::head_commit(&repo).unwrap(), head);
+        assert!(repo.find_reference("refs/tags/v1.0").is_err());
+        let other = format!("refs/remotes/origin/{}", default_branch);
+        assert!(repo.find_reference(&other).is_err());
+
+        src.checkout = super::CheckoutType::none;
+        let repo = super::git_sync_with_remote(&src, dir.join("none"), None, None).unwrap();
+        assert_eq!(super::head_commit(&repo).unwrap(), head);
+        // syncing again keeps HEAD where it is
+        let repo = super::git_sync_with_remote(&src, dir.join("none"), None, None).unwrap();
+        assert_eq!(super::head_commit(&repo).unwrap(), head);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sync_updates_submodules() {
+        use sha2::Sha256;
+
+        let dir = scratch_dir("git-submodules");
+        let lib = git2::Repository::init(dir.join("lib")).unwrap();
+        commit_files(&lib, &[("lib.txt", "1")]);
+
+        let origin = git2::Repository::init(dir.join("origin")).unwrap();
+        commit_files(&origin, &[("README", "hello")]);
+        let mut submodule = origin
+            .submodule(
+                dir.join("lib").to_str().unwrap(),
+                std::path::Path::new("vendor/lib"),
+                true,
+            )
+            .unwrap();
+        submodule.clone(None).unwrap();
+        submodule.add_finalize().unwrap();
+        commit_files(&origin, &[]);
+        tag_head(&origin, "v1.0");
+
+        let mut src = git_source(&dir.join("origin"), "v1.0", false);
+        let plain = super::git_sync_with_remote(&src, dir.join("plain"), None, None).unwrap();
+        assert!(!dir.join("plain/clone/vendor/lib/lib.txt").exists());
+
+        src.submodules = super::Submodules::Paths(vec![String::from("vendor/lib/")]);
+        let repo = super::git_sync_with_remote(&src, dir.join("sub"), None, None).unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("sub/clone/vendor/lib/lib.txt")).unwrap(),
+            b"1"
+        );
+
+        // the submodule content is part of the tree hash
+        let digest = super::tree_checksum::<Sha256>(&repo).unwrap();
+        assert_ne!(super::tree_checksum::<Sha256>(&plain).unwrap(), digest);
+        // only the commit recorded in the superproject counts, not where the
+        // submodule HEAD went since
+        let sub_repo = git2::Repository::open(dir.join("sub/clone/vendor/lib")).unwrap();
+        commit_files(&sub_repo, &[("lib.txt", "2")]);
+        assert_eq!(super::tree_checksum::<Sha256>(&repo).unwrap(), digest);
+
+        // syncing goes back to the commit recorded upstream
+        super::git_sync_with_remote(&src, dir.join("sub"), None, None).unwrap();
+        assert_eq!(super::tree_checksum::<Sha256>(&repo).unwrap(), digest);
+
+        src.submodules = super::Submodules::Paths(vec![String::from("vendor/other")]);
+        let err = super::git_sync_with_remote(&src, dir.join("sub"), None, None)
+            .err()
+            .unwrap();
+        assert!(err.message().contains("no submodule at: vendor/other"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
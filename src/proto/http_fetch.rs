@@ -0,0 +1,202 @@
+use crate::*;
+
+use indicatif::*;
+
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+pub fn url_file_name(url: &str) -> std::io::Result<String> {
+    // Strip query string and fragment
+    let cleaned = url.split(['?', '#']).next().unwrap_or(url);
+    let cleaned = match cleaned.split_once("://") {
+        Some((_, rhs)) => rhs,
+        None => cleaned,
+    };
+
+    // Extract last part after final slash, a bare host has no file name
+    match cleaned.trim_end_matches('/').rsplit_once('/') {
+        Some((_, name)) if !name.is_empty() && name != "." && name != ".." => Ok(name.to_string()),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "[{}:{}] no file name could be derived from url: {}",
+                file!(),
+                line!(),
+                url
+            ),
+        )),
+    }
+}
+
+fn setup_progress_bar(file_name: &str, len: Option<u64>) -> ProgressBar {
+    let pb = match len {
+        Some(len) => {
+            let pb = ProgressBar::new(len);
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template(
+                        "[{prefix}]: {msg}: [{bar:40.bold.dim}] {bytes}/{total_bytes} ({percent}%)",
+                    )
+                    .unwrap()
+                    .progress_chars("=> "),
+            );
+            pb
+        }
+        None => {
+            let pb = ProgressBar::new_spinner();
+            pb.set_style(
+                ProgressStyle::default_spinner()
+                    .template("[{prefix}]: {msg}: {spinner} {bytes} ({bytes_per_sec})")
+                    .unwrap(),
+            );
+            pb
+        }
+    };
+    pb.set_prefix(file_name.to_owned());
+    pb.set_message("Downloading");
+    pb
+}
+
+fn stream_to_file(url: &str, part_path: &std::path::Path, file_name: &str) -> std::io::Result<()> {
+    let mut response = ureq::get(url)
+        .call()
+        .map_err(|err| err.into_io())
+        .map_err(io_err_ctx!(url))?;
+
+    let pb = setup_progress_bar(file_name, response.body().content_length());
+    let mut reader = response.body_mut().as_reader();
+    let mut file = io_ok!(fs::File::create(part_path), part_path.to_string_lossy());
+    let mut buffer = [0u8; 8192];
+
+    while let n = io_ok!(reader.read(&mut buffer), url)
+        && n != 0
+    {
+        io_ok!(file.write_all(&buffer[..n]), part_path.to_string_lossy());
+        pb.inc(n as u64);
+    }
+
+    io_ok!(file.sync_all(), part_path.to_string_lossy());
+    pb.finish_with_message("Downloaded");
+
+    Ok(())
+}
+
+/// Downloads `url` into `dest_dir`, returning the path of the fetched file.
+///
+/// The body is streamed into a `.part` file next to the destination which is
+/// only renamed into place once the transfer completed, so an existing file is
+/// always a complete download and is reused as-is.
+pub fn http_download<DestDir: AsRef<std::path::Path>>(
+    url: &str,
+    dest_dir: DestDir,
+) -> std::io::Result<PathBuf> {
+    let file_name = url_file_name(url)?;
+    let dest_path = dest_dir.as_ref().join(&file_name);
+    println!("attempting to download: {url}");
+    println!("download path: {:?}", dest_path);
+
+    if dest_path.exists() {
+        println!("file exists, skipping download...");
+        return Ok(dest_path);
+    }
+
+    let part_path = dest_dir.as_ref().join(format!(".{}.part", file_name));
+    if let Err(err) = stream_to_file(url, &part_path, &file_name) {
+        let _ = fs::remove_file(&part_path);
+        return Err(err);
+    }
+
+    io_ok!(
+        fs::rename(&part_path, &dest_path),
+        "{} -> {}",
+        part_path.to_string_lossy(),
+        dest_path.to_string_lossy()
+    );
+
+    Ok(dest_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    // Minimal HTTP/1.1 stand-in: answers each connection with the next canned response.
+    fn serve(responses: Vec<Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    line.clear();
+                }
+                stream.write_all(&response).unwrap();
+            }
+        });
+
+        format!("http://{}", addr)
+    }
+
+    fn ok_response(body: &[u8]) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    #[test]
+    fn test_url_file_name() {
+        assert_eq!(
+            url_file_name("https://example.com/releases/pkg-1.0.tar.gz").unwrap(),
+            "pkg-1.0.tar.gz"
+        );
+        assert_eq!(
+            url_file_name("https://example.com/pkg.zip?raw=true#frag").unwrap(),
+            "pkg.zip"
+        );
+        assert!(url_file_name("https://example.com").is_err());
+        assert!(url_file_name("https://example.com/").is_err());
+    }
+
+    #[test]
+    fn test_download_follows_redirect() {
+        let base = serve(vec![
+            b"HTTP/1.1 302 Found\r\nLocation: /real/data.bin\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_vec(),
+            ok_response(b"payload"),
+        ]);
+        let dir = scratch_dir("http-redirect");
+
+        let path = http_download(&format!("{}/data.bin", base), &dir).unwrap();
+        assert_eq!(path, dir.join("data.bin"));
+        assert_eq!(fs::read(&path).unwrap(), b"payload");
+        assert!(!dir.join(".data.bin.part").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_download_leaves_no_file() {
+        let base = serve(vec![
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+            // truncated body: promised 100 bytes, sends 5
+            b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\nConnection: close\r\n\r\nshort".to_vec(),
+        ]);
+        let dir = scratch_dir("http-failure");
+
+        assert!(http_download(&format!("{}/missing.tar.gz", base), &dir).is_err());
+        assert!(http_download(&format!("{}/short.tar.gz", base), &dir).is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
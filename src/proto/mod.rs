@@ -1 +1,4 @@
+pub mod git_auth;
+pub mod git_cache;
 pub mod git_clone;
+pub mod http_fetch;
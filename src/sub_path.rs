@@ -1,8 +1,33 @@
 use crate::io_err_ctx;
 pub use std::path::Path;
+use std::path::{Component, PathBuf};
 
 pub trait SubPath {
     fn is_subpath_of<P: AsRef<Path>>(&self, base: P) -> std::io::Result<bool>;
+
+    /// Same check as `is_subpath_of` but resolves `.` and `..` lexically, so it
+    /// also works for paths that do not exist (yet). Symlinks are not followed.
+    fn is_lexical_subpath_of<P: AsRef<Path>>(&self, base: P) -> bool;
+}
+
+fn normalize_lexically(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::Normal(_) => {
+                normalized.push(component)
+            }
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+        }
+    }
+
+    Some(normalized)
 }
 
 impl SubPath for Path {
@@ -20,4 +45,41 @@ impl SubPath for Path {
         }
         Ok(false)
     }
+
+    fn is_lexical_subpath_of<P: AsRef<Path>>(&self, base: P) -> bool {
+        match (
+            normalize_lexically(base.as_ref()),
+            normalize_lexically(self),
+        ) {
+            (Some(base_norm), Some(child_norm)) => child_norm.starts_with(base_norm),
+            _ => false,
+        }
+    }
+}
+
+/// Creates `dir` and its missing parents, but only if the deepest of them that
+/// already exists resolves inside `base`, so a symlink can't redirect
+/// `create_dir_all` elsewhere. Returns `false` without creating anything if it
+/// doesn't.
+pub fn create_dir_inside<P: AsRef<Path>>(dir: &Path, base: P) -> std::io::Result<bool> {
+    let base = base.as_ref();
+    // `/` or `.` always exists, a dangling symlink is an existing entry too
+    let existing = dir
+        .ancestors()
+        .map(|dir| match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
+        })
+        .find(|dir| dir.symlink_metadata().is_ok())
+        .unwrap();
+    let base_canon = base
+        .canonicalize()
+        .map_err(io_err_ctx!(base.to_string_lossy()))?;
+    match existing.canonicalize() {
+        Ok(existing_canon) if existing_canon.starts_with(&base_canon) => (),
+        _ => return Ok(false),
+    }
+
+    std::fs::create_dir_all(dir).map_err(io_err_ctx!(dir.to_string_lossy()))?;
+    dir.is_subpath_of(base)
 }
@@ -0,0 +1,9 @@
+use crate::lua::load_lua::*;
+use crate::lua::lua_types::*;
+use crate::upkg::BuildEnv;
+use crate::*;
+
+pub fn build(lua: &Lua, pkg: &Package, env: &BuildEnv) -> LuaResult<()> {
+    call_stage_fn(lua, "Build", &env.src_dir, &stage_context(lua, pkg, env)?)?;
+    Ok(())
+}
@@ -1,40 +1,140 @@
 use crate::lua::lua_types::*;
+use crate::upkg::BuildEnv;
+use crate::upkg::lockfile::LockFile;
 use crate::*;
 
-pub fn download<P: AsRef<std::path::Path>>(pkg: &Package, pkgbuild: P) -> LuaResult<()> {
+// Sources that can't be built from without the network, one line each.
+fn missing_sources(pkg: &Package, env: &BuildEnv) -> LuaResult<Vec<String>> {
+    let mut missing = Vec::new();
+
     for src in &pkg.source.0 {
-        match src.proto {
+        let present = match src.proto {
             Proto::git => {
-                let url = &src.location;
-                let build_dir = pkgbuild
-                    .as_ref()
-                    .parent()
-                    .ok_or_else(|| {
-                        LuaError::external(format!(
-                            "[{}:{}] couldn't evaluate parent path of: {}",
-                            file!(),
-                            line!(),
-                            pkgbuild.as_ref().to_string_lossy()
-                        ))
-                    })?
-                    .join("build");
-
-                if !build_dir.exists() {
-                    io_ok!(fs::create_dir(&build_dir), build_dir.to_string_lossy());
-                }
+                git_clone::repo_dir(&src.location, &env.src_dir, src.repo_name.as_deref()).exists()
+                    || git_cache::mirror_dir(&env.config.cache_dir, &src.location).exists()
+            }
+            Proto::url => env
+                .src_dir
+                .join(io_ok!(http_fetch::url_file_name(&src.location)))
+                .exists(),
+            Proto::file => env.pkgbuild_dir.join(&src.location).exists(),
+        };
+        if !present {
+            missing.push(format!("{:?}: {}", src.proto, src.location));
+        }
+    }
+
+    Ok(missing)
+}
+
+/// Fetches the sources of `pkg`, git sources locked in `pkgbuild.lock` are
+/// checked out at their locked commit.
+pub fn download(pkg: &Package, env: &BuildEnv) -> LuaResult<()> {
+    let lock = io_ok!(LockFile::read(LockFile::path(env)));
+    fetch_sources(pkg, env, lock.as_ref())
+}
+
+pub fn fetch_sources(pkg: &Package, env: &BuildEnv, lock: Option<&LockFile>) -> LuaResult<()> {
+    let src_dir = &env.src_dir;
+
+    if env.config.offline {
+        let missing = missing_sources(pkg, env)?;
+        if !missing.is_empty() {
+            return Err(LuaError::external(format!(
+                "[{}:{}] offline and missing sources:\n  {}",
+                file!(),
+                line!(),
+                missing.join("\n  ")
+            )));
+        }
+    }
+
+    for src in &pkg.source.0 {
+        match src.proto {
+            Proto::git | Proto::url if !src_dir.exists() => {
+                io_ok!(fs::create_dir_all(src_dir), src_dir.to_string_lossy());
+            }
+            _ => (),
+        }
 
+        match src.proto {
+            Proto::git => {
+                let locked = lock
+                    .and_then(|lock| lock.find(src))
+                    .and_then(|locked| locked.commit.as_deref());
                 git_2_lua_ok!(git_clone::git_sync_with_remote(
-                    &url,
-                    build_dir,
-                    src.repo_name.as_deref(),
-                    &src.checkout
+                    src,
+                    src_dir,
+                    Some(&env.config),
+                    locked
                 ));
             }
             Proto::url => {
-                todo!("add support to fetch deps over HTTP");
+                io_ok!(http_fetch::http_download(&src.location, src_dir));
             }
             Proto::file => (),
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::test_util::scratch_dir;
+
+    #[test]
+    fn test_offline_lists_missing_sources() {
+        let dir = scratch_dir("offline");
+        fs::write(dir.join("pkgbuild.lua"), "").unwrap();
+        fs::write(dir.join("fix.patch"), "").unwrap();
+
+        let env = BuildEnv::new(
+            dir.join("pkgbuild.lua"),
+            None,
+            Config::from_layer(ConfigLayer {
+                cache_dir: Some(dir.join("cache")),
+                offline: Some(true),
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+        fs::create_dir_all(&env.src_dir).unwrap();
+        fs::write(env.src_dir.join("have.tar.gz"), "").unwrap();
+
+        let lua = Lua::new();
+        let pkg: Package = lua
+            .from_value(
+                lua.load(
+                    r#"return {
+                        pkg = { name = "hello", ver = "1.0", desc = "" },
+                        depends = {},
+                        source = {
+                            { proto = "git", url = "https://example.com/hello.git" },
+                            { proto = "url", url = "https://example.com/have.tar.gz" },
+                            { proto = "url", url = "https://example.com/missing.tar.gz" },
+                            { proto = "file", file = "fix.patch" },
+                            { proto = "file", file = "gone.patch" },
+                        },
+                        checksum = {},
+                    }"#,
+                )
+                .eval()
+                .unwrap(),
+            )
+            .unwrap();
+
+        let err = download(&pkg, &env).unwrap_err().to_string();
+        assert!(
+            err.contains("git: https://example.com/hello.git"),
+            "{}",
+            err
+        );
+        assert!(err.contains("url: https://example.com/missing.tar.gz"));
+        assert!(err.contains("file: gone.patch"));
+        assert!(!err.contains("have.tar.gz") && !err.contains("fix.patch"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
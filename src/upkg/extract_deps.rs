@@ -1,15 +1,57 @@
+use crate::archive::extract::*;
+use crate::archive::format::*;
 use crate::lua::lua_types::*;
+use crate::upkg::BuildEnv;
 use crate::*;
 
-pub fn extract<P>(pkg: &Package, _pkgbuild: P) -> LuaResult<()>
-where
-    P: AsRef<std::path::Path>,
-{
+use std::path::PathBuf;
+
+// File the top-level directory of the extracted `archive_name` is recorded in.
+fn top_level_record(env: &BuildEnv, archive_name: &str) -> PathBuf {
+    env.build_dir.join("extracted").join(archive_name)
+}
+
+/// Directory the archive `archive_name` was extracted into by the extract
+/// stage, if it had a single top-level one.
+pub fn extracted_dir(env: &BuildEnv, archive_name: &str) -> Option<PathBuf> {
+    let name = fs::read_to_string(top_level_record(env, archive_name)).ok()?;
+    Some(env.src_dir.join(name.trim_end_matches('\n'))).filter(|dir| dir.is_dir())
+}
+
+pub fn extract(pkg: &Package, env: &BuildEnv) -> LuaResult<()> {
+    let src_dir = &env.src_dir;
+
     for src in &pkg.source.0 {
         match src.proto {
             Proto::git => (),
             Proto::url => {
-                todo!("implement tarball extraction of data recvd from server");
+                let archive_name = io_ok!(http_fetch::url_file_name(&src.location));
+                let archive = src_dir.join(&archive_name);
+                let record = top_level_record(env, &archive_name);
+
+                let top_level = match io_ok!(detect_format(&archive)) {
+                    ArchiveFormat::Unknown => {
+                        println!("not an archive, skipping extraction: {:?}", archive);
+                        None
+                    }
+                    format => io_ok!(extract_archive(&archive, format, src_dir)),
+                };
+
+                match top_level {
+                    Some(dir) => {
+                        // `record` always has a parent below the build dir
+                        let parent = record.parent().unwrap();
+                        io_ok!(fs::create_dir_all(parent), parent.to_string_lossy());
+                        io_ok!(
+                            fs::write(&record, format!("{}\n", dir.to_string_lossy())),
+                            record.to_string_lossy()
+                        );
+                    }
+                    None if record.exists() => {
+                        io_ok!(fs::remove_file(&record), record.to_string_lossy());
+                    }
+                    None => (),
+                }
             }
             Proto::file => (),
         };
@@ -0,0 +1,92 @@
+use crate::config::Config;
+use crate::lua::load_lua::*;
+use crate::lua::lua_types::*;
+use crate::pkg::db::*;
+use crate::pkg::install::*;
+use crate::pkg::manifest::*;
+use crate::pkg::remove::remove_installed;
+use crate::pkg::resolve::*;
+use crate::upkg::BuildEnv;
+use crate::*;
+
+pub fn install(lua: &Lua, pkg: &Package, env: &BuildEnv) -> LuaResult<()> {
+    // always stage into a fresh pkgdir so files of a previous run don't leak in
+    if env.pkg_dir.exists() {
+        io_ok!(
+            fs::remove_dir_all(&env.pkg_dir),
+            env.pkg_dir.to_string_lossy()
+        );
+    }
+    io_ok!(
+        fs::create_dir_all(&env.pkg_dir),
+        env.pkg_dir.to_string_lossy()
+    );
+
+    call_stage_fn(lua, "Install", &env.src_dir, &stage_context(lua, pkg, env)?)?;
+    Ok(())
+}
+
+pub fn deploy(pkg: &Package, env: &BuildEnv, reason: InstallReason, nodeps: bool) -> LuaResult<()> {
+    let pkg_file = env.pkgbuild_dir.join(package_file_name(
+        &pkg.pkg.name,
+        &pkg.pkg.ver,
+        pkg.pkg.rel(),
+        pkg.pkg.arch(),
+    ));
+
+    install_package_file(&pkg_file, &env.config, reason, nodeps)
+}
+
+fn install_one(
+    db: &LocalDb,
+    pkg_file: &std::path::Path,
+    config: &Config,
+    reason: InstallReason,
+) -> LuaResult<()> {
+    let installed = io_ok!(install_package(db, pkg_file, &config.install_root, reason));
+    println!(
+        "installed {} ({} files)",
+        installed.entry_name(),
+        installed.package.files.len()
+    );
+
+    Ok(())
+}
+
+/// Installs `pkg_file` along with whatever it depends on, taken from the other
+/// packages next to it. `nodeps` installs just the package as is.
+pub fn install_package_file<P: AsRef<std::path::Path>>(
+    pkg_file: P,
+    config: &Config,
+    reason: InstallReason,
+    nodeps: bool,
+) -> LuaResult<()> {
+    let db = io_ok!(LocalDb::open(config.db_path()));
+    if nodeps {
+        return install_one(&db, pkg_file.as_ref(), config, reason);
+    }
+
+    let target = io_ok!(Available::from_file(pkg_file.as_ref()));
+    let available = match pkg_file.as_ref().parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => io_ok!(scan_packages(dir)),
+        _ => io_ok!(scan_packages(".")),
+    };
+    let installed = io_ok!(db.packages());
+    let plan = io_ok!(resolve(&installed, &available, vec![target], reason));
+
+    for name in &plan.remove {
+        println!("{} is replaced", name);
+        io_ok!(remove_installed(
+            &db,
+            &config.install_root,
+            &installed,
+            name,
+            &plan.remove
+        ));
+    }
+    for step in &plan.install {
+        install_one(&db, &step.package.pkg_file, config, step.reason)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,376 @@
+use crate::lua::lua_types::*;
+use crate::upkg::BuildEnv;
+use crate::upkg::download_deps;
+use crate::upkg::verify_deps::file_digest;
+use crate::*;
+
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use std::path::PathBuf;
+
+static LOCK_HEADER: &str = "# Generated by upkg, `upkg update` refreshes it.\n\n";
+
+/// What a source resolved to when it was locked.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LockedSource {
+    pub proto: Proto,
+    pub location: String,
+    /// What the pkgbuild asks for, e.g. `tag v1.2`, empty for the default branch.
+    #[serde(default)]
+    pub checkout: String,
+    /// Commit a git source is checked out at.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// Tag a git source was checked out from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Object the tag pointed to, the tag object itself for annotated tags.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_target: Option<String>,
+    /// File name a url source was downloaded to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// sha256 of the file, or the tree hash of a git checkout.
+    pub sha256: String,
+}
+
+impl LockedSource {
+    fn same_source(&self, src: &SourceField) -> bool {
+        self.proto == src.proto
+            && self.location == src.location
+            && self.checkout == checkout_key(&src.checkout)
+    }
+
+    fn describe(&self) -> String {
+        match self.checkout.is_empty() {
+            true => format!("{:?} {}", self.proto, self.location),
+            false => format!("{:?} {} ({})", self.proto, self.location, self.checkout),
+        }
+    }
+
+    // What has to stay the same for `other` to match the lock, the tag fields
+    // only document where the commit came from.
+    fn changes(&self, other: &LockedSource) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.commit != other.commit {
+            changes.push(format!(
+                "commit {} -> {}",
+                self.commit.as_deref().unwrap_or("none"),
+                other.commit.as_deref().unwrap_or("none")
+            ));
+        }
+        if self.file != other.file {
+            changes.push(format!(
+                "file {} -> {}",
+                self.file.as_deref().unwrap_or("none"),
+                other.file.as_deref().unwrap_or("none")
+            ));
+        }
+        if self.sha256 != other.sha256 {
+            changes.push(format!("sha256 {} -> {}", self.sha256, other.sha256));
+        }
+        changes
+    }
+}
+
+/// `pkgbuild.lock`, the sources of a pkgbuild as they were last resolved.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct LockFile {
+    #[serde(default, rename = "source")]
+    pub sources: Vec<LockedSource>,
+}
+
+/// How `checkout` is recorded in the lock file.
+pub fn checkout_key(checkout: &CheckoutType) -> String {
+    match checkout {
+        CheckoutType::tag(tag) => format!("tag {}", tag),
+        CheckoutType::branch(branch) => format!("branch {}", branch),
+        CheckoutType::commit(commit) => format!("commit {}", commit),
+        CheckoutType::rev(rev) => format!("rev {}", rev),
+        CheckoutType::none => String::new(),
+    }
+}
+
+impl LockFile {
+    /// The lock file of the pkgbuild of `env`, `pkgbuild.lock` next to it.
+    pub fn path(env: &BuildEnv) -> PathBuf {
+        env.pkgbuild.with_extension("lock")
+    }
+
+    pub fn read<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Option<LockFile>> {
+        let path_utf8 = path.as_ref().to_string_lossy();
+        if !path.as_ref().exists() {
+            return Ok(None);
+        }
+        let data = io_ok!(fs::read_to_string(path.as_ref()), path_utf8);
+
+        toml::from_str(&data).map(Some).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("[{}:{}] {}: {}", file!(), line!(), path_utf8, err),
+            )
+        })
+    }
+
+    pub fn write<P: AsRef<std::path::Path>>(&self, path: P) -> std::io::Result<()> {
+        let path_utf8 = path.as_ref().to_string_lossy();
+        let data = toml::to_string_pretty(self).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("[{}:{}] {}: {}", file!(), line!(), path_utf8, err),
+            )
+        })?;
+        io_ok!(
+            fs::write(path.as_ref(), format!("{}{}", LOCK_HEADER, data)),
+            path_utf8
+        );
+        Ok(())
+    }
+
+    /// Entry of `src`, as long as the pkgbuild still asks for the same thing.
+    pub fn find(&self, src: &SourceField) -> Option<&LockedSource> {
+        self.sources.iter().find(|locked| locked.same_source(src))
+    }
+}
+
+// The tag of `src` HEAD is at, `follow_latest` may have picked a newer one.
+fn head_tag(repo: &git2::Repository, src: &SourceField) -> Result<Option<String>, git2::Error> {
+    let CheckoutType::tag(tag) = &src.checkout else {
+        return Ok(None);
+    };
+    let head = git_ok!(git_clone::head_commit(repo));
+    let latest = git_ok!(git_clone::target_tag(repo, tag, src.follow_latest));
+
+    for name in [latest.as_str(), tag.as_str()] {
+        if let Ok(obj) = repo.revparse_single(&format!("refs/tags/{}", name))
+            && git_ok!(obj.peel_to_commit()).id() == head
+        {
+            return Ok(Some(name.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+fn resolve_source(src: &SourceField, env: &BuildEnv) -> LuaResult<LockedSource> {
+    let mut locked = LockedSource {
+        proto: src.proto,
+        location: src.location.clone(),
+        checkout: checkout_key(&src.checkout),
+        commit: None,
+        tag: None,
+        tag_target: None,
+        file: None,
+        sha256: String::new(),
+    };
+
+    match src.proto {
+        Proto::git => {
+            let repo_dir =
+                git_clone::repo_dir(&src.location, &env.src_dir, src.repo_name.as_deref());
+            let repo = git_2_lua_ok!(git2::Repository::open(&repo_dir));
+            locked.commit = Some(git_2_lua_ok!(git_clone::head_commit(&repo)).to_string());
+            locked.tag = git_2_lua_ok!(head_tag(&repo, src));
+            if let Some(tag) = &locked.tag {
+                let target = git_2_lua_ok!(repo.refname_to_id(&format!("refs/tags/{}", tag)));
+                locked.tag_target = Some(target.to_string());
+            }
+            locked.sha256 = format!(
+                "{:x}",
+                git_2_lua_ok!(git_clone::tree_checksum::<Sha256>(&repo))
+            );
+        }
+        Proto::url => {
+            let file_name = io_ok!(http_fetch::url_file_name(&src.location));
+            locked.sha256 = file_digest(env.src_dir.join(&file_name), &CheckSumKind::sha256)?;
+            locked.file = Some(file_name);
+        }
+        Proto::file => {
+            locked.sha256 =
+                file_digest(env.pkgbuild_dir.join(&src.location), &CheckSumKind::sha256)?;
+        }
+    }
+
+    Ok(locked)
+}
+
+/// Lock entries for the sources of `pkg` as they are fetched right now.
+pub fn resolve(pkg: &Package, env: &BuildEnv) -> LuaResult<LockFile> {
+    let mut lock = LockFile::default();
+    for src in &pkg.source.0 {
+        lock.sources.push(resolve_source(src, env)?);
+    }
+    Ok(lock)
+}
+
+/// Checks the fetched sources against `pkgbuild.lock` and records new or
+/// changed sources in it. With `--locked` any difference is an error, so is a
+/// url whose content changed, that needs an explicit `upkg update`.
+pub fn lock(pkg: &Package, env: &BuildEnv) -> LuaResult<()> {
+    let path = LockFile::path(env);
+    let path_utf8 = path.to_string_lossy();
+    let old = io_ok!(LockFile::read(&path)).unwrap_or_default();
+    let resolved = resolve(pkg, env)?;
+
+    let mut lock = LockFile::default();
+    let mut changes = Vec::new();
+    let mut changed_urls = Vec::new();
+    for (src, new) in pkg.source.0.iter().zip(resolved.sources) {
+        match old.find(src) {
+            None => {
+                changes.push(format!("new source: {}", new.describe()));
+                lock.sources.push(new);
+            }
+            Some(locked) => {
+                let diff = locked.changes(&new);
+                if diff.is_empty() {
+                    lock.sources.push(locked.clone());
+                    continue;
+                }
+                let change = format!("{}: {}", new.describe(), diff.join(", "));
+                if new.proto == Proto::url {
+                    changed_urls.push(change.clone());
+                }
+                changes.push(change);
+                lock.sources.push(new);
+            }
+        }
+    }
+    for locked in &old.sources {
+        if !pkg.source.0.iter().any(|src| locked.same_source(src)) {
+            changes.push(format!("removed source: {}", locked.describe()));
+        }
+    }
+
+    if changes.is_empty() {
+        return Ok(());
+    }
+    if !changed_urls.is_empty() || env.config.locked {
+        return Err(LuaError::external(format!(
+            "[{}:{}] sources differ from {} (upkg update accepts them):\n  {}",
+            file!(),
+            line!(),
+            path_utf8,
+            changes.join("\n  ")
+        )));
+    }
+
+    for change in &changes {
+        println!("lock: {}", change);
+    }
+    io_ok!(lock.write(&path));
+    Ok(())
+}
+
+/// Fetches the sources ignoring `pkgbuild.lock` and writes it anew.
+pub fn update(pkg: &Package, env: &BuildEnv) -> LuaResult<()> {
+    if env.config.locked {
+        return Err(LuaError::external(format!(
+            "[{}:{}] can't update the lock file with --locked",
+            file!(),
+            line!()
+        )));
+    }
+    download_deps::fetch_sources(pkg, env, None)?;
+
+    let path = LockFile::path(env);
+    let lock = resolve(pkg, env)?;
+    io_ok!(lock.write(&path));
+    println!("updated: {}", path.to_string_lossy());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, ConfigLayer};
+    use crate::test_util::{commit_files, scratch_dir};
+
+    // Commits `data` as VERSION and moves the `v1.0` tag onto it.
+    fn commit_tagged(repo: &git2::Repository, data: &str) -> git2::Oid {
+        let oid = commit_files(repo, &[("VERSION", data)]);
+        repo.tag_lightweight("v1.0", repo.find_commit(oid).unwrap().as_object(), true)
+            .unwrap();
+        oid
+    }
+
+    fn load_package(lua: &Lua, origin: &std::path::Path, extra: &str) -> Package {
+        let pkgbuild = format!(
+            r#"return {{
+                pkg = {{ name = "hello", ver = "1.0", desc = "" }},
+                depends = {{}},
+                source = {{
+                    {{ proto = "git", url = "{}", tag = "v1.0", repo_name = "hello" }},
+                    {}
+                }},
+                checksum = {{}},
+            }}"#,
+            origin.to_string_lossy(),
+            extra
+        );
+        lua.from_value(lua.load(pkgbuild).eval().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_lock_pins_moving_tags() {
+        let dir = scratch_dir("lock");
+        fs::write(dir.join("pkgbuild.lua"), "").unwrap();
+        fs::write(dir.join("fix.patch"), "fix").unwrap();
+        let origin = git2::Repository::init(dir.join("origin")).unwrap();
+        let first = commit_tagged(&origin, "1");
+
+        let layer = |locked| ConfigLayer {
+            cache_dir: Some(dir.join("cache")),
+            locked: Some(locked),
+            ..Default::default()
+        };
+        let env = BuildEnv::new(
+            dir.join("pkgbuild.lua"),
+            None,
+            Config::from_layer(layer(false)),
+        )
+        .unwrap();
+        let lua = Lua::new();
+        let pkg = load_package(&lua, &dir.join("origin"), "");
+
+        download_deps::download(&pkg, &env).unwrap();
+        lock(&pkg, &env).unwrap();
+        let locked = LockFile::read(dir.join("pkgbuild.lock")).unwrap().unwrap();
+        assert_eq!(locked.sources[0].commit, Some(first.to_string()));
+        assert_eq!(locked.sources[0].tag.as_deref(), Some("v1.0"));
+
+        // the moved tag is ignored until the lock is updated
+        let second = commit_tagged(&origin, "2");
+        download_deps::download(&pkg, &env).unwrap();
+        lock(&pkg, &env).unwrap();
+        assert_eq!(fs::read(env.src_dir.join("hello/VERSION")).unwrap(), b"1");
+        let relocked = LockFile::read(dir.join("pkgbuild.lock")).unwrap().unwrap();
+        assert_eq!(relocked, locked);
+
+        // new sources fail with --locked and get added without it
+        let pkg = load_package(
+            &lua,
+            &dir.join("origin"),
+            r#"{ proto = "file", file = "fix.patch" },"#,
+        );
+        let strict = BuildEnv::new(
+            dir.join("pkgbuild.lua"),
+            None,
+            Config::from_layer(layer(true)),
+        )
+        .unwrap();
+        let err = lock(&pkg, &strict).unwrap_err().to_string();
+        assert!(err.contains("new source: file fix.patch"), "{}", err);
+        lock(&pkg, &env).unwrap();
+        let locked = LockFile::read(dir.join("pkgbuild.lock")).unwrap().unwrap();
+        assert_eq!(locked.sources.len(), 2);
+
+        update(&pkg, &env).unwrap();
+        assert_eq!(fs::read(env.src_dir.join("hello/VERSION")).unwrap(), b"2");
+        let locked = LockFile::read(dir.join("pkgbuild.lock")).unwrap().unwrap();
+        assert_eq!(locked.sources[0].commit, Some(second.to_string()));
+        lock(&pkg, &strict).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,141 @@
-// download source -> verify() -> extract source -> prepare() -> build() -> test() -> install()
+// download source -> lock -> verify() -> extract source -> patch -> prepare() -> build() -> test() -> install() -> package
 pub mod download_deps;
 pub mod verify_deps;
 pub mod extract_deps;
+pub mod patch_deps;
 pub mod prepare_deps;
 pub mod build_deps;
 pub mod test_deps;
 pub mod install_deps;
+pub mod package_deps;
+pub mod lockfile;
+
+use crate::config::Config;
+use crate::lua::lua_types::*;
+use crate::pkg::db::{InstallReason, LocalDb};
+use crate::pkg::resolve::check_build_deps;
+use crate::*;
+
+use std::path::PathBuf;
+
+/// Locations a single pkgbuild is processed with. Sources are fetched into and
+/// built from `src_dir`, `Install()` stages the package tree into `pkg_dir`.
+#[derive(Debug)]
+pub struct BuildEnv {
+    pub pkgbuild: PathBuf,
+    pub pkgbuild_dir: PathBuf,
+    pub build_dir: PathBuf,
+    pub src_dir: PathBuf,
+    pub pkg_dir: PathBuf,
+    pub config: Config,
+}
+
+impl BuildEnv {
+    pub fn new<P: AsRef<std::path::Path>>(
+        pkgbuild: P,
+        build_dir: Option<PathBuf>,
+        config: Config,
+    ) -> std::io::Result<BuildEnv> {
+        let pkgbuild = io_ok!(
+            pkgbuild.as_ref().canonicalize(),
+            pkgbuild.as_ref().to_string_lossy()
+        );
+        if !pkgbuild.is_file() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "[{}:{}] pkgbuild is not a file: {}",
+                    file!(),
+                    line!(),
+                    pkgbuild.to_string_lossy()
+                ),
+            ));
+        }
+
+        // a canonical file path always has a parent
+        let pkgbuild_dir = pkgbuild.parent().unwrap().to_path_buf();
+        let build_dir = build_dir.unwrap_or_else(|| config.build_dir_for(&pkgbuild_dir));
+        let src_dir = build_dir.join("src");
+        let pkg_dir = build_dir.join("pkg");
+
+        Ok(BuildEnv {
+            pkgbuild,
+            pkgbuild_dir,
+            build_dir,
+            src_dir,
+            pkg_dir,
+            config,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Deps { check: bool },
+    Download,
+    Lock,
+    Update,
+    Verify,
+    Extract,
+    Patch,
+    Prepare,
+    Build,
+    Check,
+    Install,
+    Package,
+    Deploy { reason: InstallReason, nodeps: bool },
+}
+
+impl Stage {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Stage::Deps { .. } => "Checking dependencies",
+            Stage::Download => "Downloading Deps",
+            Stage::Lock => "Checking pkgbuild.lock",
+            Stage::Update => "Updating pkgbuild.lock",
+            Stage::Verify => "Verifying Deps",
+            Stage::Extract => "Extracting Deps",
+            Stage::Patch => "Applying patches",
+            Stage::Prepare => "Preparing",
+            Stage::Build => "Building",
+            Stage::Check => "Checking",
+            Stage::Install => "Installing into pkgdir",
+            Stage::Package => "Creating package",
+            Stage::Deploy { .. } => "Installing package",
+        }
+    }
+
+    pub fn run(&self, lua: &Lua, pkg: &Package, env: &BuildEnv) -> LuaResult<()> {
+        match self {
+            Stage::Deps { check } => check_deps(pkg, env, *check),
+            Stage::Download => download_deps::download(pkg, env),
+            Stage::Lock => lockfile::lock(pkg, env),
+            Stage::Update => lockfile::update(pkg, env),
+            Stage::Verify => verify_deps::verify(pkg, env),
+            Stage::Extract => extract_deps::extract(pkg, env),
+            Stage::Patch => patch_deps::patch(pkg, env),
+            Stage::Prepare => prepare_deps::prepare(lua, pkg, env),
+            Stage::Build => build_deps::build(lua, pkg, env),
+            Stage::Check => test_deps::test(lua, pkg, env),
+            Stage::Install => install_deps::install(lua, pkg, env),
+            Stage::Package => package_deps::package(pkg, env),
+            Stage::Deploy { reason, nodeps } => install_deps::deploy(pkg, env, *reason, *nodeps),
+        }
+    }
+}
+
+fn check_deps(pkg: &Package, env: &BuildEnv, check: bool) -> LuaResult<()> {
+    let db = io_ok!(LocalDb::open_read_only(env.config.db_path()));
+    io_ok!(check_build_deps(pkg, &io_ok!(db.packages()), check));
+    Ok(())
+}
+
+/// Runs `stages` in order, printing a `(step/total)` header before each one.
+pub fn run_stages(lua: &Lua, pkg: &Package, env: &BuildEnv, stages: &[Stage]) -> LuaResult<()> {
+    let total_steps = stages.len();
+    for (idx, stage) in stages.iter().enumerate() {
+        println!("({}/{}) {}", idx + 1, total_steps, stage.description());
+        stage.run(lua, pkg, env)?;
+    }
+    Ok(())
+}
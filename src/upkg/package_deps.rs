@@ -0,0 +1,21 @@
+use crate::lua::lua_types::*;
+use crate::pkg::manifest::*;
+use crate::pkg::package_file::*;
+use crate::upkg::BuildEnv;
+use crate::*;
+
+pub fn package(pkg: &Package, env: &BuildEnv) -> LuaResult<()> {
+    let manifest = io_ok!(Manifest::from_staging(
+        pkg,
+        &env.pkg_dir,
+        &env.config.packager
+    ));
+    println!(
+        "packaging {} files ({} bytes)",
+        manifest.files.len(),
+        manifest.installed_size
+    );
+
+    io_ok!(create_package(&manifest, &env.pkg_dir, &env.pkgbuild_dir));
+    Ok(())
+}
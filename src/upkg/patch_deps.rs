@@ -0,0 +1,139 @@
+use crate::lua::lua_types::*;
+use crate::patch::{self, Outcome};
+use crate::upkg::BuildEnv;
+use crate::*;
+
+use git2::{ApplyLocation, ApplyOptions, Diff, Repository};
+
+// Applies `data` with git when it applies cleanly, `false` leaves it to the
+// native code which tells an applied patch from a rejected one.
+fn git_apply(repo: &Repository, data: &[u8]) -> Result<bool, git2::Error> {
+    let Ok(diff) = Diff::from_buffer(data) else {
+        return Ok(false);
+    };
+    let mut check = ApplyOptions::new();
+    check.check(true);
+    if repo
+        .apply(&diff, ApplyLocation::WorkDir, Some(&mut check))
+        .is_err()
+    {
+        return Ok(false);
+    }
+    git_ok!(repo.apply(&diff, ApplyLocation::WorkDir, None));
+    Ok(true)
+}
+
+/// Applies the patch sources of `pkg`, in order, inside `src_dir`. Git
+/// checkouts are patched with git so renames and mode changes work too.
+pub fn patch(pkg: &Package, env: &BuildEnv) -> LuaResult<()> {
+    for src in pkg.source.0.iter().filter(|src| src.is_patch()) {
+        let patch_file = env.pkgbuild_dir.join(&src.location);
+        let target = env.src_dir.join(src.apply_to.as_deref().unwrap_or("."));
+        let strip = src.strip.unwrap_or(1);
+        if !target.is_dir() {
+            return Err(LuaError::external(format!(
+                "[{}:{}] can't apply {}, not a directory: {}",
+                file!(),
+                line!(),
+                src.location,
+                target.to_string_lossy()
+            )));
+        }
+        let data = io_ok!(fs::read(&patch_file), patch_file.to_string_lossy());
+
+        // git only knows the `a/` and `b/` prefixes of its own diffs
+        if strip == 1
+            && let Ok(repo) = Repository::open(&target)
+            && git_2_lua_ok!(git_apply(&repo, &data))
+        {
+            println!("applied with git: {}", src.location);
+            continue;
+        }
+
+        let text = io_ok!(
+            String::from_utf8(data)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
+            patch_file.to_string_lossy()
+        );
+        let patches = io_ok!(patch::parse(&text), patch_file.to_string_lossy());
+        match io_ok!(
+            patch::apply(&patches, &target, strip as usize),
+            src.location
+        ) {
+            Outcome::Applied => println!("applied: {}", src.location),
+            Outcome::AlreadyApplied => println!("already applied, skipping: {}", src.location),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::test_util::scratch_dir;
+
+    #[test]
+    fn test_patches_git_checkouts_once() {
+        let dir = scratch_dir("patch-src");
+        fs::write(dir.join("pkgbuild.lua"), "").unwrap();
+        fs::write(
+            dir.join("fix.diff"),
+            "--- a/hello.txt\n+++ b/hello.txt\n@@ -1 +1 @@\n-helo\n+hello\n",
+        )
+        .unwrap();
+        let env = BuildEnv::new(
+            dir.join("pkgbuild.lua"),
+            None,
+            Config::from_layer(ConfigLayer {
+                cache_dir: Some(dir.join("cache")),
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+
+        let checkout = env.src_dir.join("hello");
+        let repo = Repository::init(&checkout).unwrap();
+        fs::write(checkout.join("hello.txt"), "helo\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("hello.txt")).unwrap();
+        index.write().unwrap();
+
+        let lua = Lua::new();
+        let pkg: Package = lua
+            .from_value(
+                lua.load(
+                    r#"return {
+                        pkg = { name = "hello", ver = "1.0", desc = "" },
+                        depends = {},
+                        source = {
+                            { proto = "file", file = "fix.diff", apply_to = "hello" },
+                        },
+                        checksum = {},
+                    }"#,
+                )
+                .eval()
+                .unwrap(),
+            )
+            .unwrap();
+        pkg.validate().unwrap();
+
+        patch(&pkg, &env).unwrap();
+        assert_eq!(
+            fs::read_to_string(checkout.join("hello.txt")).unwrap(),
+            "hello\n"
+        );
+        // the second run finds the patch applied already
+        patch(&pkg, &env).unwrap();
+        assert_eq!(
+            fs::read_to_string(checkout.join("hello.txt")).unwrap(),
+            "hello\n"
+        );
+
+        fs::write(checkout.join("hello.txt"), "bye\n").unwrap();
+        let err = patch(&pkg, &env).unwrap_err().to_string();
+        assert!(err.contains("hello.txt: @@ -1,1 +1,1 @@"), "{}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use crate::lua::lua_types::*;
+use crate::upkg::BuildEnv;
 use crate::*;
 
 use crypto_common::Output;
@@ -7,7 +8,7 @@ use sha2::{Digest, Sha256, Sha512};
 
 use std::io::Read;
 
-fn calc_checksum<P, ShaType>(path: P) -> std::io::Result<Output<ShaType>>
+pub fn calc_checksum<P, ShaType>(path: P) -> std::io::Result<Output<ShaType>>
 where
     P: AsRef<std::path::Path>,
     ShaType: Digest,
@@ -25,68 +26,147 @@ where
     Ok(hasher.finalize())
 }
 
-fn match_sha256<P: AsRef<std::path::Path>>(file: P, digest: &str) -> LuaResult<()> {
-    let sha256 = std::format!("{:x}", calc_checksum::<&P, Sha256>(&file)?);
-    println!("verifying sha256 for: {:?}", file.as_ref());
-    if digest != sha256 {
-        return Err(LuaError::external(format!(
-            "[{}:{}] sha256 mismatch: file: {}, expected: {}, got: {}",
-            file!(),
-            line!(),
-            file.as_ref().to_string_lossy(),
+/// Lowercase hex digest of the file at `path`.
+pub fn file_digest<P: AsRef<std::path::Path>>(
+    path: P,
+    kind: &CheckSumKind,
+) -> std::io::Result<String> {
+    let path_utf8 = path.as_ref().to_string_lossy();
+    Ok(match kind {
+        CheckSumKind::sha256 => format!(
+            "{:x}",
+            io_ok!(calc_checksum::<_, Sha256>(path.as_ref()), path_utf8)
+        ),
+        CheckSumKind::sha512 => format!(
+            "{:x}",
+            io_ok!(calc_checksum::<_, Sha512>(path.as_ref()), path_utf8)
+        ),
+    })
+}
+
+fn mismatch_err(what: &str, kind: &CheckSumKind, expected: &str, got: &str) -> LuaError {
+    LuaError::external(format!(
+        "[{}:{}] {:?} mismatch: {}, expected: {}, got: {}",
+        file!(),
+        line!(),
+        kind,
+        what,
+        expected,
+        got
+    ))
+}
+
+fn match_file<P: AsRef<std::path::Path>>(
+    file: P,
+    kind: &CheckSumKind,
+    digest: &str,
+) -> LuaResult<()> {
+    println!("verifying {:?} for: {:?}", kind, file.as_ref());
+    let file_digest = file_digest(file.as_ref(), kind)?;
+    if !digest.eq_ignore_ascii_case(&file_digest) {
+        return Err(mismatch_err(
+            &format!("file: {}", file.as_ref().to_string_lossy()),
+            kind,
             digest,
-            sha256
-        )));
+            &file_digest,
+        ));
     }
     Ok(())
 }
 
-fn match_sha512<P: AsRef<std::path::Path>>(file: P, digest: &str) -> LuaResult<()> {
-    let sha512 = std::format!("{:x}", calc_checksum::<&P, Sha512>(&file)?);
-    println!("verifying sha512 for: {:?}", file.as_ref());
-    if digest != sha512 {
+// Moves a download that failed verification out of the way, so it isn't used
+// by later stages and gets fetched again on the next run.
+fn quarantine(file: &std::path::Path) -> LuaResult<std::path::PathBuf> {
+    let mut bad_name = file.as_os_str().to_owned();
+    bad_name.push(".bad");
+    let bad_file = std::path::PathBuf::from(bad_name);
+    io_ok!(
+        fs::rename(file, &bad_file),
+        "{} -> {}",
+        file.to_string_lossy(),
+        bad_file.to_string_lossy()
+    );
+    Ok(bad_file)
+}
+
+fn match_git_tree(repo: &git2::Repository, kind: &CheckSumKind, digest: &str) -> LuaResult<()> {
+    let tree_digest = match kind {
+        CheckSumKind::sha256 => format!(
+            "{:x}",
+            git_2_lua_ok!(git_clone::tree_checksum::<Sha256>(repo))
+        ),
+        CheckSumKind::sha512 => format!(
+            "{:x}",
+            git_2_lua_ok!(git_clone::tree_checksum::<Sha512>(repo))
+        ),
+    };
+    println!("verifying {:?} tree hash for: {:?}", kind, repo.path());
+    if !digest.eq_ignore_ascii_case(&tree_digest) {
+        return Err(mismatch_err(
+            &format!("tree of repo: {}", repo.path().to_string_lossy()),
+            kind,
+            digest,
+            &tree_digest,
+        ));
+    }
+    Ok(())
+}
+
+fn open_git_source(source: &SourceField, env: &BuildEnv) -> LuaResult<git2::Repository> {
+    let repo_dir = git_clone::repo_dir(&source.location, &env.src_dir, source.repo_name.as_deref());
+    Ok(git_2_lua_ok!(git2::Repository::open(&repo_dir)))
+}
+
+fn match_pin(source: &SourceField, pin: &str, env: &BuildEnv) -> LuaResult<()> {
+    let repo = open_git_source(source, env)?;
+    let head = git_2_lua_ok!(git_clone::head_commit(&repo)).to_string();
+    println!("verifying pinned commit {} for: {}", pin, source.location);
+    if !head.starts_with(&pin.to_ascii_lowercase()) {
         return Err(LuaError::external(format!(
-            "[{}:{}] sha256 mismatch: file: {}, expected: {}, got: {}",
+            "[{}:{}] pinned commit mismatch: repo: {}, expected: {}, got: {}",
             file!(),
             line!(),
-            file.as_ref().to_string_lossy(),
-            digest,
-            sha512
+            source.location,
+            pin,
+            head
         )));
     }
     Ok(())
 }
 
-pub fn verify<P>(pkg: &Package, pkgbuild: P) -> LuaResult<()>
-where
-    P: AsRef<std::path::Path>,
-{
+pub fn verify(pkg: &Package, env: &BuildEnv) -> LuaResult<()> {
+    for source in &pkg.source.0 {
+        if let (Proto::git, Some(pin)) = (&source.proto, &source.pin) {
+            match_pin(source, pin, env)?;
+        }
+    }
+
     for (idx, chksum_field) in pkg.checksum.0.iter().enumerate() {
         match chksum_field {
             CheckSumField::Skip => (),
             CheckSumField::Value { kind, digest } => {
                 let source = &pkg.source.0[idx];
                 match source.proto {
-                    Proto::git => todo!("implement checksum validation for git"),
-                    Proto::url => todo!("implement checksum validation for url's"),
+                    Proto::git => match_git_tree(&open_git_source(source, env)?, kind, digest)?,
+                    Proto::url => {
+                        let file_loc = env
+                            .src_dir
+                            .join(io_ok!(http_fetch::url_file_name(&source.location)));
+
+                        if let Err(err) = match_file(&file_loc, kind, digest) {
+                            if !file_loc.exists() {
+                                return Err(err);
+                            }
+                            let bad_file = quarantine(&file_loc)?;
+                            return Err(LuaError::external(format!(
+                                "{}\nmoved the download to {}, it is fetched again on the next run",
+                                err,
+                                bad_file.to_string_lossy()
+                            )));
+                        }
+                    }
                     Proto::file => {
-                        let file_loc = pkgbuild
-                            .as_ref()
-                            .parent()
-                            .ok_or_else(|| {
-                                LuaError::external(format!(
-                                    "[{}:{}] couldn't evaluate parent path of: {}",
-                                    file!(),
-                                    line!(),
-                                    pkgbuild.as_ref().to_string_lossy()
-                                ))
-                            })?
-                            .join(&source.location);
-
-                        match kind {
-                            CheckSumKind::sha256 => match_sha256(&file_loc, digest)?,
-                            CheckSumKind::sha512 => match_sha512(&file_loc, digest)?,
-                        };
+                        match_file(env.pkgbuild_dir.join(&source.location), kind, digest)?
                     }
                 }
             }
@@ -94,3 +174,48 @@ where
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::test_util::scratch_dir;
+
+    #[test]
+    fn test_bad_download_is_quarantined() {
+        let dir = scratch_dir("verify-url");
+        fs::write(dir.join("pkgbuild.lua"), "").unwrap();
+        let env = BuildEnv::new(
+            dir.join("pkgbuild.lua"),
+            None,
+            Config::from_layer(ConfigLayer::default()),
+        )
+        .unwrap();
+        fs::create_dir_all(&env.src_dir).unwrap();
+        fs::write(env.src_dir.join("hello.tar.gz"), b"tampered").unwrap();
+
+        let lua = Lua::new();
+        let pkg: Package = lua
+            .from_value(
+                lua.load(
+                    r#"return {
+                        pkg = { name = "hello", ver = "1.0", desc = "" },
+                        depends = {},
+                        source = { { proto = "url", url = "https://example.com/hello.tar.gz" } },
+                        checksum = { { kind = "sha256", digest = "00ff" } },
+                    }"#,
+                )
+                .eval()
+                .unwrap(),
+            )
+            .unwrap();
+
+        let err = verify(&pkg, &env).unwrap_err().to_string();
+        assert!(err.contains("expected: 00ff"), "{}", err);
+        assert!(err.contains(&format!("got: {:x}", Sha256::digest(b"tampered"))));
+        assert!(!env.src_dir.join("hello.tar.gz").exists());
+        assert!(env.src_dir.join("hello.tar.gz.bad").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,494 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+/// `[epoch:]ver[-rel]`, ordered like pacman's `vercmp`: epochs first, then the
+/// version, and the release only when both sides have one.
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub epoch: u64,
+    pub ver: String,
+    pub rel: Option<String>,
+}
+
+fn version_err(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+// Splits off a leading `<digits>:` epoch, anything else is part of the version.
+fn split_epoch(ver: &str) -> (u64, &str) {
+    match ver.split_once(':') {
+        Some((epoch, rest)) if !epoch.is_empty() && epoch.bytes().all(|b| b.is_ascii_digit()) => {
+            (epoch.parse().unwrap_or(u64::MAX), rest)
+        }
+        _ => (0, ver),
+    }
+}
+
+// Takes the leading run of digits or letters off `ver`.
+fn take_segment(ver: &[u8], numeric: bool) -> (&[u8], &[u8]) {
+    let len = ver
+        .iter()
+        .take_while(|b| match numeric {
+            true => b.is_ascii_digit(),
+            false => b.is_ascii_alphabetic(),
+        })
+        .count();
+    ver.split_at(len)
+}
+
+fn skip_separators(ver: &[u8]) -> &[u8] {
+    let len = ver
+        .iter()
+        .take_while(|b| !b.is_ascii_alphanumeric())
+        .count();
+    &ver[len..]
+}
+
+/// rpm's segment wise comparison of a version without epoch and release:
+/// numbers compare numerically and beat letters, and a trailing letter segment
+/// sorts before the end of the string, so `1.0rc1 < 1.0 < 1.0.1`.
+pub fn rpmvercmp(lhs: &str, rhs: &str) -> Ordering {
+    if lhs == rhs {
+        return Ordering::Equal;
+    }
+
+    let mut one = lhs.as_bytes();
+    let mut two = rhs.as_bytes();
+
+    while !one.is_empty() && !two.is_empty() {
+        let one_sep = skip_separators(one);
+        let two_sep = skip_separators(two);
+        if one_sep.is_empty() || two_sep.is_empty() {
+            one = one_sep;
+            two = two_sep;
+            break;
+        }
+        // `1.0a` vs `1.0.1`, the side with more separators is newer
+        let one_skipped = one.len() - one_sep.len();
+        let two_skipped = two.len() - two_sep.len();
+        if one_skipped != two_skipped {
+            return one_skipped.cmp(&two_skipped);
+        }
+
+        let numeric = one_sep[0].is_ascii_digit();
+        let (one_seg, one_rest) = take_segment(one_sep, numeric);
+        let (two_seg, two_rest) = take_segment(two_sep, numeric);
+
+        // segments of different type, numbers are newer than letters
+        if two_seg.is_empty() {
+            return match numeric {
+                true => Ordering::Greater,
+                false => Ordering::Less,
+            };
+        }
+
+        let ordering = if numeric {
+            let one_num = &one_seg[one_seg.iter().take_while(|b| **b == b'0').count()..];
+            let two_num = &two_seg[two_seg.iter().take_while(|b| **b == b'0').count()..];
+            one_num
+                .len()
+                .cmp(&two_num.len())
+                .then_with(|| one_num.cmp(two_num))
+        } else {
+            one_seg.cmp(two_seg)
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+
+        one = one_rest;
+        two = two_rest;
+    }
+
+    if one.is_empty() && two.is_empty() {
+        return Ordering::Equal;
+    }
+
+    // a remaining letter segment never beats the end of the other string, but
+    // one behind a separator does, so `1.0 < 1.0.a` just like in pacman
+    let one_alpha = one.first().is_some_and(|b| b.is_ascii_alphabetic());
+    let two_alpha = two.first().is_some_and(|b| b.is_ascii_alphabetic());
+    if (one.is_empty() && !two_alpha) || one_alpha {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
+
+// `1.0-rc1` -> `1.0rc1`, separators in front of a letter segment are dropped.
+fn join_prerelease(ver: &str) -> String {
+    let mut joined = String::with_capacity(ver.len());
+    let mut rest = ver;
+    while let Some(sep) = rest.find(|c: char| !c.is_ascii_alphanumeric()) {
+        let (head, tail) = rest.split_at(sep);
+        joined.push_str(head);
+        let next = tail
+            .find(|c: char| c.is_ascii_alphanumeric())
+            .unwrap_or(tail.len());
+        if !tail[next..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            joined.push_str(&tail[..next]);
+        }
+        rest = &tail[next..];
+    }
+    joined.push_str(rest);
+    joined
+}
+
+fn check_version_str(ver: &str) -> std::io::Result<()> {
+    if ver.is_empty() {
+        return Err(version_err(format!(
+            "[{}:{}] empty version",
+            file!(),
+            line!()
+        )));
+    }
+    if let Some(bad) = ver
+        .chars()
+        .find(|c| c.is_whitespace() || matches!(c, '<' | '>' | '=' | ','))
+    {
+        return Err(version_err(format!(
+            "[{}:{}] invalid character {:?} in version: {}",
+            file!(),
+            line!(),
+            bad,
+            ver
+        )));
+    }
+    Ok(())
+}
+
+impl Version {
+    /// Version of a package, `ver` may carry an epoch (`1:2.0`) but no release.
+    pub fn new(ver: &str, rel: u32) -> Version {
+        let (epoch, ver) = split_epoch(ver);
+        Version {
+            epoch,
+            ver: ver.to_string(),
+            rel: Some(rel.to_string()),
+        }
+    }
+
+    // Like `parse` without validation, for strings that were checked before.
+    fn lenient(version: &str) -> Version {
+        let (epoch, rest) = split_epoch(version);
+        let (ver, rel) = match rest.rsplit_once('-') {
+            Some((ver, rel)) => (ver, Some(rel.to_string())),
+            None => (rest, None),
+        };
+        Version {
+            epoch,
+            ver: ver.to_string(),
+            rel,
+        }
+    }
+
+    /// Parses `[epoch:]ver[-rel]`.
+    pub fn parse(version: &str) -> std::io::Result<Version> {
+        check_version_str(version)?;
+        let parsed = Version::lenient(version);
+        if parsed.ver.is_empty() || parsed.rel.as_deref() == Some("") {
+            return Err(version_err(format!(
+                "[{}:{}] invalid version: {}",
+                file!(),
+                line!(),
+                version
+            )));
+        }
+        Ok(parsed)
+    }
+
+    /// Version of a git tag, prefixes such as `v` or `release-` are dropped and
+    /// a `-` is kept as part of the version instead of starting a release.
+    /// Separators in front of letters are dropped as well, in a tag `1.0-rc1`
+    /// is a release candidate of `1.0` while rpmvercmp would sort it after.
+    pub fn from_tag(tag: &str) -> Version {
+        let ver = match tag.find(|c: char| c.is_ascii_digit()) {
+            Some(start) => &tag[start..],
+            None => tag,
+        };
+        Version {
+            epoch: 0,
+            ver: join_prerelease(ver),
+            rel: None,
+        }
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Version) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| rpmvercmp(&self.ver, &other.ver))
+            .then_with(|| match (&self.rel, &other.rel) {
+                (Some(lhs), Some(rhs)) => rpmvercmp(lhs, rhs),
+                _ => Ordering::Equal,
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Version) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Version) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.epoch > 0 {
+            write!(f, "{}:", self.epoch)?;
+        }
+        write!(f, "{}", self.ver)?;
+        if let Some(rel) = &self.rel {
+            write!(f, "-{}", rel)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+}
+
+impl CmpOp {
+    // Splits a leading operator off `req`, a bare version means `=`.
+    fn split(req: &str) -> (CmpOp, &str) {
+        for (prefix, op) in [
+            (">=", CmpOp::Ge),
+            ("<=", CmpOp::Le),
+            ("==", CmpOp::Eq),
+            (">", CmpOp::Gt),
+            ("<", CmpOp::Lt),
+            ("=", CmpOp::Eq),
+        ] {
+            if let Some(rest) = req.strip_prefix(prefix) {
+                return (op, rest);
+            }
+        }
+        (CmpOp::Eq, req)
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Eq => "=",
+            CmpOp::Ge => ">=",
+            CmpOp::Gt => ">",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Constraint {
+    pub op: CmpOp,
+    pub version: Version,
+}
+
+impl Constraint {
+    pub fn matches(&self, version: &Version) -> bool {
+        let ordering = version.cmp(&self.version);
+        match self.op {
+            CmpOp::Lt => ordering == Ordering::Less,
+            CmpOp::Le => ordering != Ordering::Greater,
+            CmpOp::Eq => ordering == Ordering::Equal,
+            CmpOp::Ge => ordering != Ordering::Less,
+            CmpOp::Gt => ordering == Ordering::Greater,
+        }
+    }
+}
+
+/// Comma separated constraints that all have to hold, e.g. `>=1.80,<2`. An
+/// empty requirement matches any version.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionReq(pub Vec<Constraint>);
+
+impl VersionReq {
+    pub fn parse(req: &str) -> std::io::Result<VersionReq> {
+        if req.trim().is_empty() {
+            return Ok(VersionReq::default());
+        }
+
+        let mut constraints = Vec::new();
+        for part in req.split(',') {
+            let (op, ver) = CmpOp::split(part.trim());
+            let version = Version::parse(ver.trim()).map_err(|err| {
+                version_err(format!(
+                    "[{}:{}] invalid version constraint {:?}: {}",
+                    file!(),
+                    line!(),
+                    req,
+                    err
+                ))
+            })?;
+            constraints.push(Constraint { op, version });
+        }
+
+        Ok(VersionReq(constraints))
+    }
+
+    pub fn is_any(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn matches(&self, version: &Version) -> bool {
+        self.0.iter().all(|constraint| constraint.matches(version))
+    }
+}
+
+impl fmt::Display for VersionReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, constraint) in self.0.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}{}", constraint.op.as_str(), constraint.version)?;
+        }
+        Ok(())
+    }
+}
+
+/// A package name with an optional version requirement, `glibc>=2.38`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    pub name: String,
+    pub req: VersionReq,
+}
+
+/// Position of the first comparison operator in a `name<op>ver` string.
+pub fn constraint_start(dep: &str) -> Option<usize> {
+    dep.find(['<', '>', '='])
+}
+
+impl Dependency {
+    pub fn parse(dep: &str) -> std::io::Result<Dependency> {
+        let (name, req) = match constraint_start(dep) {
+            Some(start) => dep.split_at(start),
+            None => (dep, ""),
+        };
+        Dependency::with_req(name, req)
+    }
+
+    pub fn with_req(name: &str, req: &str) -> std::io::Result<Dependency> {
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(version_err(format!(
+                "[{}:{}] invalid package name {:?}",
+                file!(),
+                line!(),
+                name
+            )));
+        }
+
+        Ok(Dependency {
+            name: name.to_string(),
+            req: VersionReq::parse(req)?,
+        })
+    }
+
+    /// Version a `provides` entry like `libfoo=1.2` stands in for, unversioned
+    /// provisions only satisfy unversioned dependencies.
+    pub fn provided_version(&self) -> Option<&Version> {
+        match self.req.0.as_slice() {
+            [
+                Constraint {
+                    op: CmpOp::Eq,
+                    version,
+                },
+            ] => Some(version),
+            _ => None,
+        }
+    }
+
+    /// Whether a package (or provision) `name` at `version` fulfills this.
+    pub fn satisfied_by(&self, name: &str, version: Option<&Version>) -> bool {
+        if self.name != name {
+            return false;
+        }
+        match version {
+            Some(version) => self.req.matches(version),
+            None => self.req.is_any(),
+        }
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.name, self.req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vercmp(lhs: &str, rhs: &str) -> Ordering {
+        Version::lenient(lhs).cmp(&Version::lenient(rhs))
+    }
+
+    #[test]
+    fn test_vercmp() {
+        let cases = [
+            ("1.0", "1.0", Ordering::Equal),
+            ("1.0", "1.0.1", Ordering::Less),
+            ("1.0rc1", "1.0", Ordering::Less),
+            ("1.0alpha", "1.0beta", Ordering::Less),
+            ("1.0a", "1.0.1", Ordering::Less),
+            ("1.10", "1.9", Ordering::Greater),
+            ("1.001", "1.1", Ordering::Equal),
+            ("1.0", "1..0", Ordering::Less),
+            ("1.0", "1.0.a", Ordering::Less),
+            ("1.0a", "1.0", Ordering::Less),
+            ("1.0.rc1", "1.0", Ordering::Greater),
+            ("1:1.0", "2.0", Ordering::Greater),
+            ("1.0-1", "1.0-2", Ordering::Less),
+            ("1.0", "1.0-2", Ordering::Equal),
+            ("2.0-1", "1.0-5", Ordering::Greater),
+        ];
+        for (lhs, rhs, expected) in cases {
+            assert_eq!(vercmp(lhs, rhs), expected, "{} vs {}", lhs, rhs);
+            assert_eq!(vercmp(rhs, lhs), expected.reverse(), "{} vs {}", rhs, lhs);
+        }
+    }
+
+    #[test]
+    fn test_dependency_constraints() {
+        let dep = Dependency::parse("glibc>=2.38").unwrap();
+        assert_eq!(dep.name, "glibc");
+        assert!(dep.satisfied_by("glibc", Some(&Version::new("2.40", 1))));
+        assert!(!dep.satisfied_by("glibc", Some(&Version::new("2.37", 3))));
+        assert!(!dep.satisfied_by("musl", Some(&Version::new("2.40", 1))));
+
+        let rust = Dependency::with_req("rust", ">=1.80,<2").unwrap();
+        assert!(rust.satisfied_by("rust", Some(&Version::new("1.85.0", 1))));
+        assert!(!rust.satisfied_by("rust", Some(&Version::new("2.0", 1))));
+        assert!(!rust.satisfied_by("rust", None));
+        assert_eq!(rust.to_string(), "rust>=1.80,<2");
+
+        assert!(Dependency::parse("bad name").is_err());
+        assert!(Dependency::parse("rust>=").is_err());
+        assert!(Dependency::with_req("rust", ">=1.80,<2 3").is_err());
+    }
+
+    #[test]
+    fn test_tag_versions() {
+        assert!(Version::from_tag("v1.0rc1") < Version::from_tag("v1.0"));
+        assert!(Version::from_tag("release-1.9") < Version::from_tag("release-1.10"));
+        assert!(Version::from_tag("v1.0-rc1") < Version::from_tag("v1.0"));
+        assert!(Version::from_tag("v1.0.beta.2") < Version::from_tag("v1.0"));
+        assert!(Version::from_tag("v1.0-rc.2") > Version::from_tag("v1.0-rc.1"));
+        assert_eq!(Version::from_tag("v2.0-rc.1").ver, "2.0rc.1");
+    }
+}